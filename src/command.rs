@@ -0,0 +1,79 @@
+use rltk::Point;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+
+use crate::gui::LevelUpSelection;
+use crate::player::{apply_level_up_bonus, get_item, search, try_move_player};
+use crate::{containers, shop, WantsToDropItem, WantsToThrowItem, WantsToUseItem};
+
+/// Something the player decided to do that changes the game. Everything the player does goes
+/// through one of these, so a run can be recorded and replayed exactly.
+///
+/// Entities are referred to by id, which comes out the same when a run is replayed from its seed.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Command {
+    /// Moves, attacks or bumps into something in a direction
+    Move { delta_x: i32, delta_y: i32 },
+    Wait,
+    PickUp,
+    Search,
+    UseItem { item: u32, target: Option<Point> },
+    DropItem { item: u32 },
+    ThrowItem { item: u32, target: Point },
+    Buy { vendor: u32, item: u32 },
+    Sell { vendor: u32, item: u32 },
+    Take { container: u32, item: u32 },
+    Put { container: u32, item: u32 },
+    LevelUp(LevelUpSelection),
+}
+
+/// Carries out a command for the player, returning true if it took their turn
+pub fn apply(ecs: &mut World, command: &Command) -> bool {
+    let player_entity = *ecs.fetch::<Entity>();
+    let entity = |id: u32| ecs.entities().entity(id);
+    let pair = |a: u32, b: u32| (entity(a), entity(b));
+
+    match *command {
+        Command::Move { delta_x, delta_y } => return try_move_player(delta_x, delta_y, ecs),
+        Command::Wait => return true,
+        Command::PickUp => return get_item(ecs),
+        Command::Search => return search(ecs),
+        Command::UseItem { item, target } => {
+            let item = entity(item);
+            let mut use_item = ecs.write_storage::<WantsToUseItem>();
+            use_item.insert(player_entity, WantsToUseItem { item, target }).expect("Unable to insert use item intent");
+            return true;
+        }
+        Command::DropItem { item } => {
+            let item = entity(item);
+            let mut drop_item = ecs.write_storage::<WantsToDropItem>();
+            drop_item.insert(player_entity, WantsToDropItem { item }).expect("Unable to insert item to drop");
+            return true;
+        }
+        Command::ThrowItem { item, target } => {
+            let item = entity(item);
+            let mut throw_item = ecs.write_storage::<WantsToThrowItem>();
+            throw_item.insert(player_entity, WantsToThrowItem { item, target }).expect("Unable to insert throw");
+            return true;
+        }
+        Command::Buy { vendor, item } => {
+            let (vendor, item) = pair(vendor, item);
+            shop::buy(ecs, vendor, item);
+        }
+        Command::Sell { vendor, item } => {
+            let (vendor, item) = pair(vendor, item);
+            shop::sell(ecs, vendor, item);
+        }
+        Command::Take { container, item } => {
+            let (container, item) = pair(container, item);
+            containers::take(ecs, container, item);
+        }
+        Command::Put { container, item } => {
+            let (container, item) = pair(container, item);
+            containers::put(ecs, container, item);
+        }
+        Command::LevelUp(selection) => apply_level_up_bonus(ecs, selection),
+    }
+    // Trading, looting and levelling up happen in menus that pause the game
+    false
+}
@@ -1,10 +1,10 @@
-use std::time::SystemTime;
-
-use rltk::RGB;
+use rltk::{RandomNumberGenerator, RGB};
 use serde::{Deserialize, Serialize};
 use specs::prelude::*;
-use specs::saveload::ConvertSaveload;
-use specs_derive::Component;
+use std::convert::Infallible as NoError;
+use std::fmt;
+use specs::saveload::{ConvertSaveload, Marker};
+use specs_derive::{Component, ConvertSaveload};
 
 #[derive(Component, ConvertSaveload)]
 pub struct Position {
@@ -25,11 +25,102 @@ pub struct Name {
     pub name: String,
 }
 
-#[derive(Component, Debug, Serialize, Deserialize)]
+/// A line or two about what something is, shown when looking at it
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct Description {
+    pub text: String,
+}
+
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct BlocksTile {}
 
-#[derive(Component, Debug, Serialize, Deserialize)]
-pub struct Item {}
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Item {
+    pub weight: f32,
+    /// What shops sell it for, in gold
+    pub value: i32,
+}
+
+/// Gold carried by something, or lying in a pile when on an item
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Gold {
+    pub amount: i32,
+}
+
+/// Sells the items in its backpack and buys the player's
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Vendor {}
+
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct WantsToTrade {
+    pub vendor: Entity,
+}
+
+/// Holds items inside it, such as a chest
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Container {}
+
+/// A container that needs a key to open
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Locked {}
+
+/// Unlocks a locked container, and is used up doing so
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Key {}
+
+#[derive(Component, Debug, Clone, ConvertSaveload)]
+pub struct InContainer {
+    pub container: Entity,
+}
+
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct WantsToOpen {
+    pub container: Entity,
+}
+
+/// Not drawn or shown in tooltips until the player spots it
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Hidden {}
+
+/// Goes off when something steps onto its tile, such as a trap
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct EntryTrigger {}
+
+/// Marks that something moved this tick, so entry triggers know to check its tile
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct EntityMoved {}
+
+/// Holds whatever triggers it in place for a number of turns
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Immobilizes {
+    pub turns: i32,
+}
+
+/// Stuck in place for this many more turns
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Immobilized {
+    pub turns: i32,
+}
+
+/// Reveals the whole map when read
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct MagicMapper {}
+
+/// Grants telepathy for a number of turns when used
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct GrantsTelepathy {
+    pub turns: i32,
+}
+
+/// Can sense monsters anywhere on the map for this many more turns
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Telepathy {
+    pub turns: i32,
+}
+
+/// Sends whatever triggers it to a random spot on the map
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Teleports {}
 
 #[derive(Component, Debug, Clone, ConvertSaveload)]
 pub struct InBackpack {
@@ -47,12 +138,27 @@ pub struct ProvidesHealing {
     pub heal_amount : i32
 }
 
-#[derive(Component, Debug, Serialize, Deserialize)]
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct Consumable {}
 
 #[derive(Component, Debug, ConvertSaveload)]
 pub struct WantsToUseItem {
     pub item: Entity,
+    /// Where to aim items that affect a tile, such as offensive scrolls
+    pub target: Option<rltk::Point>,
+}
+
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct WantsToThrowItem {
+    pub item: Entity,
+    pub target: rltk::Point,
+}
+
+/// Items that hurt whatever they are thrown at
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct ThrowingWeapon {
+    pub damage: rltk::DiceType,
+    pub damage_type: DamageType,
 }
 
 #[derive(Component, Debug, Clone, ConvertSaveload)]
@@ -63,7 +169,10 @@ pub struct WantsToDropItem {
 #[derive(Component, Debug, ConvertSaveload)]
 pub struct MovementSpeed {
     pub min_delay_ms: u64,
-    pub last_move_time: Option<SystemTime>,
+    /// Game clock time of the last move
+    pub last_move_time: Option<u64>,
+    /// Extra delay from carrying too much, kept up to date by the EncumbranceSystem
+    pub encumbrance_delay_ms: u64,
 }
 
 #[derive(Component, Debug, ConvertSaveload)]
@@ -74,11 +183,128 @@ pub struct CombatStats {
     pub power: i32,
 }
 
+/// Character level, and the experience earned towards the next one
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct Experience {
+    pub level: i32,
+    pub xp: i32,
+}
+
+impl Experience {
+    pub fn xp_to_next_level(&self) -> i32 {
+        self.level * 500
+    }
+
+    /// Experience awarded for killing something of this level
+    pub fn xp_for_kill(&self) -> i32 {
+        self.level * 100
+    }
+}
+
 #[derive(Component, Debug, Clone, ConvertSaveload)]
 pub struct WantsToMelee {
     pub target: Entity,
 }
 
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum DamageType {
+    Bludgeoning,
+    Slashing,
+    Fire,
+    Cold,
+    Poison,
+    Magic,
+    /// Damage from going hungry, which nothing can resist
+    Starvation,
+}
+
+impl fmt::Display for DamageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DamageType::Bludgeoning => "bludgeoning",
+            DamageType::Slashing => "slashing",
+            DamageType::Fire => "fire",
+            DamageType::Cold => "cold",
+            DamageType::Poison => "poison",
+            DamageType::Magic => "magic",
+            DamageType::Starvation => "starvation",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A creature's built-in melee attack, such as fists or claws
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct NaturalAttack {
+    pub name: String,
+    pub hit_bonus: i32,
+    pub damage: rltk::DiceType,
+    pub damage_type: DamageType,
+}
+
+/// Percentage of each damage type that is shrugged off. Negative values are vulnerabilities that
+/// take extra damage, and 100 or more is immunity.
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct Resistances {
+    pub modifiers: Vec<(DamageType, i32)>,
+}
+
+impl Resistances {
+    /// Applies any resistance or vulnerability to an incoming amount of damage
+    pub fn adjust(&self, amount: i32, damage_type: DamageType) -> i32 {
+        if damage_type == DamageType::Starvation {
+            return amount;
+        }
+        let percent: i32 = self.modifiers.iter().filter(|(t, _)| *t == damage_type).map(|(_, pct)| pct).sum();
+        i32::max(0, amount * (100 - percent) / 100)
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum LootDrop {
+    Nothing,
+    HealthPotion,
+    Rations,
+    MagicMissileScroll,
+    Gold,
+}
+
+/// Weighted table of what an entity drops when it dies. Weighting `Nothing` makes drops a chance
+/// rather than a certainty.
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct LootTable {
+    pub drops: Vec<(LootDrop, i32)>,
+}
+
+impl LootTable {
+    pub fn roll(&self, rng: &mut RandomNumberGenerator) -> LootDrop {
+        let total: i32 = self.drops.iter().map(|(_, weight)| weight).sum();
+        if total <= 0 {
+            return LootDrop::Nothing;
+        }
+        let mut roll = rng.roll_dice(1, total);
+        for (drop, weight) in self.drops.iter() {
+            if roll <= *weight {
+                return *drop;
+            }
+            roll -= weight;
+        }
+        LootDrop::Nothing
+    }
+}
+
+/// Splits into two smaller copies of itself when killed
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct SplitsOnDeath {}
+
+/// Bursts when killed, damaging everything within `radius` tiles
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct ExplodesOnDeath {
+    pub damage: i32,
+    pub damage_type: DamageType,
+    pub radius: i32,
+}
+
 #[derive(Component, Debug, ConvertSaveload)]
 pub struct Ranged {
     pub range : i32
@@ -86,26 +312,82 @@ pub struct Ranged {
 
 #[derive(Component, Debug, ConvertSaveload)]
 pub struct InflictsDamage {
-    pub damage : i32
+    pub damage : i32,
+    pub damage_type: DamageType,
 }
 
-#[derive(Component, Debug, ConvertSaveload)]
+/// A single hit of damage, before resistances are applied
+#[derive(Debug, Clone)]
+pub struct DamageInstance {
+    pub amount: i32,
+    pub damage_type: DamageType,
+    /// Who dealt the damage, if anyone
+    pub source: Option<Entity>,
+}
+
+#[derive(Component, Debug)]
 pub struct SufferDamage {
-    pub amount: Vec<i32>,
+    pub amount: Vec<DamageInstance>,
 }
 
 impl SufferDamage {
-    pub fn new_damage(store: &mut WriteStorage<SufferDamage>, victim: Entity, amount: i32) {
+    pub fn new_damage(
+        store: &mut WriteStorage<SufferDamage>,
+        victim: Entity,
+        amount: i32,
+        damage_type: DamageType,
+        source: Option<Entity>,
+    ) {
+        let damage = DamageInstance { amount, damage_type, source };
         if let Some(suffering) = store.get_mut(victim) {
-            suffering.amount.push(amount);
+            suffering.amount.push(damage);
         } else {
-            let dmg = SufferDamage { amount: vec![amount] };
+            let dmg = SufferDamage { amount: vec![damage] };
             store.insert(victim, dmg).expect("Unable to insert damage");
         }
     }
 }
 
-#[derive(Component, Debug, Serialize, Deserialize)]
+// The derive can't see through the Option<Entity> inside a Vec, so SufferDamage converts by hand
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SufferDamageData<M> {
+    pub amount: Vec<(i32, DamageType, Option<M>)>,
+}
+
+impl<M: Marker + Serialize> ConvertSaveload<M> for SufferDamage
+where
+    for<'de> M: Deserialize<'de>,
+{
+    type Data = SufferDamageData<M>;
+    type Error = NoError;
+
+    fn convert_into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+    where
+        F: FnMut(Entity) -> Option<M>,
+    {
+        let amount = self.amount.iter().map(|d| (d.amount, d.damage_type, d.source.and_then(&mut ids))).collect();
+        Ok(SufferDamageData { amount })
+    }
+
+    fn convert_from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+    where
+        F: FnMut(M) -> Option<Entity>,
+    {
+        let amount = data
+            .amount
+            .into_iter()
+            .map(|(amount, damage_type, source)| DamageInstance {
+                amount,
+                damage_type,
+                source: source.and_then(&mut ids),
+            })
+            .collect();
+        Ok(SufferDamage { amount })
+    }
+}
+
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct Player {}
 
 #[derive(Component, ConvertSaveload)]
@@ -115,9 +397,27 @@ pub struct Viewshed {
     pub dirty: bool,
 }
 
-#[derive(Component, Debug, Serialize, Deserialize)]
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct Monster {}
 
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum HungerState {
+    WellFed,
+    Normal,
+    Hungry,
+    Starving,
+}
+
+/// Counts down the player's turns until they get hungrier
+#[derive(Component, Debug, ConvertSaveload)]
+pub struct HungerClock {
+    pub state: HungerState,
+    pub duration: i32,
+}
+
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct ProvidesFood {}
+
 // Serialization helper code. We need to implement ConvertSaveload for each type that contains an
 // Entity.
 
@@ -126,5 +426,54 @@ pub struct SerializeMe;
 // Special component that exists to help serialize the game data
 #[derive(Component, Serialize, Deserialize, Clone)]
 pub struct SerializationHelper {
-    pub map : super::map::Map
+    pub map : super::map::Map,
+    pub identification: crate::identification::Identification,
+    /// The most recent messages, so a resumed game shows what just happened
+    pub log: Vec<crate::gamelog::LogEntry>,
+    /// Movement delays are measured against the clock, so it carries on from where it was
+    pub clock: crate::movement_util::GameClock,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resistances_scale_damage_of_their_type() {
+        let resistances = Resistances { modifiers: vec![(DamageType::Fire, 50), (DamageType::Cold, -50)] };
+        assert_eq!(resistances.adjust(10, DamageType::Fire), 5);
+        assert_eq!(resistances.adjust(10, DamageType::Cold), 15);
+        assert_eq!(resistances.adjust(10, DamageType::Slashing), 10);
+    }
+
+    #[test]
+    fn resistances_stack_but_never_heal() {
+        let resistances = Resistances { modifiers: vec![(DamageType::Poison, 75), (DamageType::Poison, 50)] };
+        assert_eq!(resistances.adjust(10, DamageType::Poison), 0);
+        let resistances = Resistances { modifiers: vec![(DamageType::Poison, 25), (DamageType::Poison, 25)] };
+        assert_eq!(resistances.adjust(10, DamageType::Poison), 5);
+    }
+
+    #[test]
+    fn loot_table_only_rolls_weighted_drops() {
+        let mut rng = RandomNumberGenerator::seeded(3);
+        let drops = vec![(LootDrop::MagicMissileScroll, 1), (LootDrop::Rations, 0), (LootDrop::HealthPotion, 3)];
+        let table = LootTable { drops };
+        let rolls: Vec<LootDrop> = (0..200).map(|_| table.roll(&mut rng)).collect();
+        assert!(rolls.iter().all(|drop| matches!(drop, LootDrop::MagicMissileScroll | LootDrop::HealthPotion)));
+        assert!(rolls.contains(&LootDrop::MagicMissileScroll) && rolls.contains(&LootDrop::HealthPotion));
+    }
+
+    #[test]
+    fn empty_loot_table_drops_nothing() {
+        let mut rng = RandomNumberGenerator::seeded(3);
+        assert_eq!(LootTable { drops: Vec::new() }.roll(&mut rng), LootDrop::Nothing);
+        assert_eq!(LootTable { drops: vec![(LootDrop::Rations, 0)] }.roll(&mut rng), LootDrop::Nothing);
+    }
+
+    #[test]
+    fn starvation_ignores_resistances() {
+        let resistances = Resistances { modifiers: vec![(DamageType::Starvation, 100)] };
+        assert_eq!(resistances.adjust(3, DamageType::Starvation), 3);
+    }
 }
@@ -0,0 +1,72 @@
+use specs::{Entity, Join, World, WorldExt};
+
+use crate::components::{Gold, InBackpack, InContainer, Key, Locked};
+use crate::encumbrance_system::can_carry;
+use crate::gamelog::LogCategory;
+use crate::identification::display_name;
+use crate::{GameLog, Name};
+
+/// Tries to open a container for the player, using up one of their keys if it is locked. Returns
+/// true if the container is open.
+pub fn try_open(ecs: &mut World, container: Entity) -> bool {
+    if !ecs.read_storage::<Locked>().contains(container) {
+        return true;
+    }
+
+    let player_entity = *ecs.fetch::<Entity>();
+    let container_name = ecs.read_storage::<Name>().get(container).unwrap().name.clone();
+    let key = {
+        let entities = ecs.entities();
+        let backpack = ecs.read_storage::<InBackpack>();
+        let keys = ecs.read_storage::<Key>();
+        (&entities, &backpack, &keys).join().find(|(_, pack, _)| pack.owner == player_entity).map(|(e, _, _)| e)
+    };
+
+    let mut log = ecs.fetch_mut::<GameLog>();
+    match key {
+        Some(key) => {
+            let key_name = ecs.read_storage::<Name>().get(key).unwrap().name.clone();
+            log.add(LogCategory::Item, format!("You unlock the {} with the {}.", container_name, key_name));
+            ecs.write_storage::<Locked>().remove(container);
+            ecs.entities().delete(key).expect("Delete failed");
+            true
+        }
+        None => {
+            log.add(LogCategory::Item, format!("The {} is locked.", container_name));
+            false
+        }
+    }
+}
+
+/// Moves an item from a container into the player's backpack. Gold goes straight into their wallet.
+pub fn take(ecs: &mut World, container: Entity, item: Entity) {
+    let player_entity = *ecs.fetch::<Entity>();
+    let item_name = display_name(ecs, item);
+    let container_name = ecs.read_storage::<Name>().get(container).unwrap().name.clone();
+    // Taking is held to the same maximum load as picking up
+    if !can_carry(ecs, player_entity, item) {
+        ecs.fetch_mut::<GameLog>().add(LogCategory::Item, format!("The {} is too heavy to carry.", item_name));
+        return;
+    }
+    ecs.write_storage::<InContainer>().remove(item);
+
+    let pile = ecs.read_storage::<Gold>().get(item).map(|pile| pile.amount);
+    if let (Some(amount), Some(wallet)) = (pile, ecs.write_storage::<Gold>().get_mut(player_entity)) {
+        wallet.amount += amount;
+        ecs.entities().delete(item).expect("Delete failed");
+    } else {
+        ecs.write_storage::<InBackpack>()
+            .insert(item, InBackpack { owner: player_entity })
+            .expect("Unable to take item");
+    }
+    ecs.fetch_mut::<GameLog>().add(LogCategory::Item, format!("You take {} from the {}.", item_name, container_name));
+}
+
+/// Moves an item from the player's backpack into a container
+pub fn put(ecs: &mut World, container: Entity, item: Entity) {
+    let item_name = display_name(ecs, item);
+    let container_name = ecs.read_storage::<Name>().get(container).unwrap().name.clone();
+    ecs.write_storage::<InBackpack>().remove(item);
+    ecs.write_storage::<InContainer>().insert(item, InContainer { container }).expect("Unable to store item");
+    ecs.fetch_mut::<GameLog>().add(LogCategory::Item, format!("You put {} in the {}.", item_name, container_name));
+}
@@ -1,44 +1,354 @@
-use specs::{Entity, Join, System, World, WorldExt, WriteStorage};
+use std::collections::HashMap;
 
-use crate::{CombatStats, GameLog, Player, SufferDamage};
+use rltk::RandomNumberGenerator;
+use specs::{Entities, Entity, Join, ReadStorage, RunNow, System, World, WorldExt, WriteExpect, WriteStorage};
+
+use crate::components::{DamageType, Experience, ExplodesOnDeath, LootDrop, LootTable, Resistances, SplitsOnDeath};
+use crate::gamelog::LogCategory;
+use crate::identification::Identification;
+use crate::player::gain_experience;
+use crate::{spawner, CombatStats, GameLog, InBackpack, Name, Player, Position, SufferDamage};
+
+/// Emitted by the DamageSystem when something takes a killing blow
+#[derive(Debug, Clone, Copy)]
+pub struct DeathEvent {
+    pub victim: Entity,
+    /// Whoever dealt the killing blow, if anyone
+    pub killer: Option<Entity>,
+}
+
+/// Deaths this tick, waiting to be handled by delete_the_dead
+#[derive(Default)]
+pub struct DeathEvents {
+    pub events: Vec<DeathEvent>,
+}
 
 pub struct DamageSystem {}
 
 impl<'a> System<'a> for DamageSystem {
-    type SystemData = (WriteStorage<'a, CombatStats>, WriteStorage<'a, SufferDamage>);
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, CombatStats>,
+        WriteStorage<'a, SufferDamage>,
+        ReadStorage<'a, Resistances>,
+        WriteExpect<'a, DeathEvents>,
+    );
 
-    fn run(&mut self, (mut combat_stats, mut suffer_damage): Self::SystemData) {
-        for (mut stats, damage) in (&mut combat_stats, &suffer_damage).join() {
-            stats.hp -= damage.amount.iter().sum::<i32>();
+    fn run(&mut self, (entities, mut combat_stats, mut suffer_damage, resistances, mut deaths): Self::SystemData) {
+        for (entity, stats, damage) in (&entities, &mut combat_stats, &suffer_damage).join() {
+            let resistances = resistances.get(entity);
+            for instance in damage.amount.iter() {
+                let was_alive = stats.hp > 0;
+                stats.hp -= resisted_damage(resistances, instance.amount, instance.damage_type);
+                if was_alive && stats.hp < 1 {
+                    deaths.events.push(DeathEvent { victim: entity, killer: instance.source });
+                }
+            }
         }
         suffer_damage.clear();
     }
 }
 
-pub fn delete_the_dead(ecs: &mut World) {
-    let mut dead: Vec<Entity> = Vec::new();
+/// How much damage actually lands on something with the given resistances
+pub fn resisted_damage(resistances: Option<&Resistances>, amount: i32, damage_type: DamageType) -> i32 {
+    match resistances {
+        Some(resistances) => resistances.adjust(amount, damage_type),
+        None => amount,
+    }
+}
+
+/// Removes dead entities, runs their on-death triggers and awards experience to the player for
+/// their kills, returning how many levels the player gained
+pub fn delete_the_dead(ecs: &mut World) -> i32 {
+    let mut xp_gained = 0;
+    // Explosions hurt whatever is nearby, which can set off more of them, so this carries on until
+    // nothing else dies
+    loop {
+        xp_gained += bury_the_dead(ecs);
+        DamageSystem {}.run_now(ecs);
+        if ecs.fetch::<DeathEvents>().events.is_empty() {
+            break;
+        }
+    }
+
+    if xp_gained > 0 {
+        gain_experience(ecs, xp_gained)
+    } else {
+        0
+    }
+}
+
+/// Removes the entities that have died so far and runs their on-death triggers, returning the
+/// experience the player earned from them
+fn bury_the_dead(ecs: &mut World) -> i32 {
+    let killers: HashMap<Entity, Option<Entity>> =
+        ecs.write_resource::<DeathEvents>().events.drain(..).map(|d| (d.victim, d.killer)).collect();
+    let mut dead: Vec<DeathEvent> = Vec::new();
+    let mut xp_gained = 0;
     // using a scope to make the borrow checker happy
     {
         let combat_stats = ecs.read_storage::<CombatStats>();
+        let experience = ecs.read_storage::<Experience>();
         let players = ecs.read_storage::<Player>();
+        let names = ecs.read_storage::<Name>();
         let entities = ecs.entities();
+        let player_entity = ecs.fetch::<Entity>();
         let mut log = ecs.fetch_mut::<GameLog>();
         for (entity, stats) in (&entities, &combat_stats).join() {
             if stats.hp < 1 {
                 let player = players.get(entity);
                 match player {
                     None => {
-                        dead.push(entity);
+                        let killer = killers.get(&entity).copied().flatten();
+                        if killer == Some(*player_entity) {
+                            if let Some(victim_exp) = experience.get(entity) {
+                                xp_gained += victim_exp.xp_for_kill();
+                            }
+                        }
+                        if let Some(victim_name) = names.get(entity) {
+                            let message = match killer.and_then(|k| names.get(k)) {
+                                Some(killer_name) => format!("{} is killed by {}.", victim_name.name, killer_name.name),
+                                None => format!("{} dies.", victim_name.name),
+                            };
+                            log.add(LogCategory::Combat, message);
+                        }
+                        dead.push(DeathEvent { victim: entity, killer });
                     }
                     Some(_) => {
-                        log.entries.push_back("You are dead!".to_string());
+                        log.add(LogCategory::Combat, "You are dead!");
                     }
                 }
             }
         }
     }
 
-    for victim in dead {
-        ecs.delete_entity(victim).expect("Unable to delete");
+    trigger_on_death(ecs, &dead);
+    leave_remains(ecs, &dead);
+
+    for death in dead {
+        ecs.delete_entity(death.victim).expect("Unable to delete");
+    }
+    xp_gained
+}
+
+/// Runs loot drops, splitting and explosions for entities that just died, while they still exist
+fn trigger_on_death(ecs: &mut World, deaths: &[DeathEvent]) {
+    let mut loot: Vec<(LootDrop, i32, i32)> = Vec::new();
+    let mut splits: Vec<(i32, i32)> = Vec::new();
+    let mut explosions: Vec<(DeathEvent, i32, i32)> = Vec::new();
+    {
+        let positions = ecs.read_storage::<Position>();
+        let loot_tables = ecs.read_storage::<LootTable>();
+        let splitters = ecs.read_storage::<SplitsOnDeath>();
+        let exploders = ecs.read_storage::<ExplodesOnDeath>();
+        let mut rng = ecs.write_resource::<RandomNumberGenerator>();
+        for death in deaths.iter() {
+            let Some(pos) = positions.get(death.victim) else {
+                continue;
+            };
+            if let Some(table) = loot_tables.get(death.victim) {
+                loot.push((table.roll(&mut rng), pos.x, pos.y));
+            }
+            if splitters.contains(death.victim) {
+                splits.push((pos.x, pos.y));
+            }
+            if exploders.contains(death.victim) {
+                explosions.push((*death, pos.x, pos.y));
+            }
+        }
+    }
+
+    for (drop, x, y) in loot {
+        spawner::loot_drop(ecs, drop, x, y);
+    }
+
+    for (x, y) in splits {
+        spawner::split_slime(ecs, x, y);
+    }
+
+    for (death, x, y) in explosions {
+        explode(ecs, death, x, y);
+    }
+}
+
+/// Drops everything the dead were carrying onto their tile and leaves a corpse behind, unless the
+/// body was destroyed by splitting or exploding
+fn leave_remains(ecs: &mut World, deaths: &[DeathEvent]) {
+    let mut corpses: Vec<(String, i32, i32)> = Vec::new();
+    {
+        let entities = ecs.entities();
+        let names = ecs.read_storage::<Name>();
+        let splitters = ecs.read_storage::<SplitsOnDeath>();
+        let exploders = ecs.read_storage::<ExplodesOnDeath>();
+        let mut positions = ecs.write_storage::<Position>();
+        let mut backpack = ecs.write_storage::<InBackpack>();
+        let mut log = ecs.fetch_mut::<GameLog>();
+        let identification = ecs.fetch::<Identification>();
+
+        for death in deaths.iter() {
+            let Some(pos) = positions.get(death.victim).map(|p| (p.x, p.y)) else {
+                continue;
+            };
+
+            let carried: Vec<Entity> =
+                (&entities, &backpack).join().filter(|(_, pack)| pack.owner == death.victim).map(|(e, _)| e).collect();
+            for item in carried {
+                backpack.remove(item);
+                positions.insert(item, Position { x: pos.0, y: pos.1 }).expect("Unable to drop item");
+                if let Some(item_name) = names.get(item) {
+                    let item_name = identification.name_of(&item_name.name);
+                    log.add(LogCategory::Item, format!("{} falls to the floor.", item_name));
+                }
+            }
+
+            let destroyed = splitters.contains(death.victim) || exploders.contains(death.victim);
+            if let Some(name) = names.get(death.victim).filter(|_| !destroyed) {
+                corpses.push((name.name.clone(), pos.0, pos.1));
+            }
+        }
+    }
+
+    for (name, x, y) in corpses {
+        spawner::corpse(ecs, &name, x, y);
+    }
+}
+
+/// Damages everything around an exploding corpse. The blast is credited to whoever killed it, so
+/// chain reactions still award experience.
+fn explode(ecs: &mut World, death: DeathEvent, x: i32, y: i32) {
+    let exploders = ecs.read_storage::<ExplodesOnDeath>();
+    let positions = ecs.read_storage::<Position>();
+    let combat_stats = ecs.read_storage::<CombatStats>();
+    let names = ecs.read_storage::<Name>();
+    let entities = ecs.entities();
+    let mut suffer_damage = ecs.write_storage::<SufferDamage>();
+    let mut log = ecs.fetch_mut::<GameLog>();
+
+    let Some(explosion) = exploders.get(death.victim) else {
+        return;
+    };
+    if let Some(name) = names.get(death.victim) {
+        log.add(LogCategory::Combat, format!("{} explodes!", name.name));
+    }
+
+    for (entity, pos, stats) in (&entities, &positions, &combat_stats).join() {
+        let in_range = i32::abs(pos.x - x) <= explosion.radius && i32::abs(pos.y - y) <= explosion.radius;
+        if entity != death.victim && stats.hp > 0 && in_range {
+            SufferDamage::new_damage(&mut suffer_damage, entity, explosion.damage, explosion.damage_type, death.killer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::saveload::{SimpleMarker, SimpleMarkerAllocator};
+    use specs::Builder;
+
+    use super::*;
+    use crate::components::{Consumable, Description, ProvidesFood, SerializeMe};
+    use crate::{Item, Renderable};
+
+    /// Just enough of a world for things to die in and leave remains, with a level 1 player
+    fn test_world() -> World {
+        let mut ecs = World::new();
+        ecs.register::<Position>();
+        ecs.register::<Name>();
+        ecs.register::<CombatStats>();
+        ecs.register::<Experience>();
+        ecs.register::<Player>();
+        ecs.register::<LootTable>();
+        ecs.register::<SplitsOnDeath>();
+        ecs.register::<ExplodesOnDeath>();
+        ecs.register::<SufferDamage>();
+        ecs.register::<Resistances>();
+        ecs.register::<InBackpack>();
+        ecs.register::<Renderable>();
+        ecs.register::<Item>();
+        ecs.register::<Consumable>();
+        ecs.register::<ProvidesFood>();
+        ecs.register::<Description>();
+        ecs.register::<SimpleMarker<SerializeMe>>();
+        ecs.insert(SimpleMarkerAllocator::<SerializeMe>::new());
+        ecs.insert(RandomNumberGenerator::seeded(1));
+        ecs.insert(GameLog::new(10));
+        ecs.insert(DeathEvents::default());
+        ecs.insert(Identification::default());
+        let player = ecs
+            .create_entity()
+            .with(Player {})
+            .with(Name { name: "Player".to_string() })
+            .with(CombatStats { max_hp: 30, hp: 30, defense: 2, power: 5 })
+            .with(Experience { level: 1, xp: 0 })
+            .build();
+        ecs.insert(player);
+        ecs
+    }
+
+    fn spawn_victim(ecs: &mut World, name: &str, x: i32, y: i32) -> Entity {
+        ecs.create_entity()
+            .with(Position { x, y })
+            .with(Name { name: name.to_string() })
+            .with(CombatStats { max_hp: 10, hp: 0, defense: 0, power: 1 })
+            .with(Experience { level: 1, xp: 0 })
+            .with(LootTable { drops: vec![(LootDrop::Rations, 1)] })
+            .build()
+    }
+
+    fn names_at(ecs: &World, x: i32, y: i32) -> Vec<String> {
+        let names = ecs.read_storage::<Name>();
+        let positions = ecs.read_storage::<Position>();
+        (&names, &positions).join().filter(|(_, pos)| pos.x == x && pos.y == y).map(|(n, _)| n.name.clone()).collect()
+    }
+
+    #[test]
+    fn kills_are_credited_and_leave_loot_where_the_victim_fell() {
+        let mut ecs = test_world();
+        let player = *ecs.fetch::<Entity>();
+        let victim = spawn_victim(&mut ecs, "Test Orc", 1, 1);
+        ecs.write_resource::<DeathEvents>().events.push(DeathEvent { victim, killer: Some(player) });
+
+        delete_the_dead(&mut ecs);
+
+        assert!(!ecs.entities().is_alive(victim));
+        let log = ecs.fetch::<GameLog>();
+        assert!(log.entries.iter().any(|entry| entry.segments[0].text == "Test Orc is killed by Player."));
+        // Loot is rolled before the victim is deleted, so it still knows where to drop it
+        let mut remains = names_at(&ecs, 1, 1);
+        remains.sort();
+        assert_eq!(remains, ["Rations", "Test Orc Corpse"]);
+        assert!(ecs.read_storage::<Experience>().get(player).unwrap().xp > 0);
+    }
+
+    #[test]
+    fn deaths_without_a_killer_give_no_experience() {
+        let mut ecs = test_world();
+        let player = *ecs.fetch::<Entity>();
+        spawn_victim(&mut ecs, "Test Orc", 1, 1);
+
+        delete_the_dead(&mut ecs);
+
+        let log = ecs.fetch::<GameLog>();
+        assert!(log.entries.iter().any(|entry| entry.segments[0].text == "Test Orc dies."));
+        assert_eq!(ecs.read_storage::<Experience>().get(player).unwrap().xp, 0);
+    }
+    #[test]
+    fn explosions_set_each_other_off_in_the_same_tick() {
+        let mut ecs = test_world();
+        let blast = || ExplodesOnDeath { damage: 5, damage_type: DamageType::Fire, radius: 1 };
+        let first = spawn_victim(&mut ecs, "Test Bomb", 1, 1);
+        ecs.write_storage::<ExplodesOnDeath>().insert(first, blast()).unwrap();
+        let second = spawn_victim(&mut ecs, "Test Bomb", 2, 1);
+        ecs.write_storage::<ExplodesOnDeath>().insert(second, blast()).unwrap();
+        // Only the second bomb is close enough to reach this one
+        let bystander = spawn_victim(&mut ecs, "Test Orc", 3, 1);
+        for entity in [second, bystander] {
+            ecs.write_storage::<CombatStats>().get_mut(entity).unwrap().hp = 3;
+        }
+
+        delete_the_dead(&mut ecs);
+
+        for entity in [first, second, bystander] {
+            assert!(!ecs.entities().is_alive(entity));
+        }
     }
 }
@@ -0,0 +1,91 @@
+use std::ops::Deref;
+
+use specs::prelude::*;
+use specs::storage::MaskedStorage;
+
+use crate::components::{InBackpack, Item};
+use crate::gamelog::LogCategory;
+use crate::{CombatStats, GameLog, MovementSpeed};
+
+/// Weight anyone can carry before their power is taken into account
+const BASE_CARRY_CAPACITY: f32 = 15.0;
+const CARRY_CAPACITY_PER_POWER: f32 = 5.0;
+/// Nothing can pick up more than this multiple of their carry capacity
+const MAX_LOAD_MULTIPLIER: f32 = 2.0;
+
+/// How much weight something can carry before being slowed down
+pub fn carry_capacity(stats: &CombatStats) -> f32 {
+    BASE_CARRY_CAPACITY + stats.power as f32 * CARRY_CAPACITY_PER_POWER
+}
+
+/// Total weight of everything in an entity's backpack
+pub fn carried_weight<D>(owner: Entity, backpack: &Storage<InBackpack, D>, items: &ReadStorage<Item>) -> f32
+where
+    D: Deref<Target = MaskedStorage<InBackpack>>,
+{
+    (backpack, items).join().filter(|(pack, _)| pack.owner == owner).map(|(_, item)| item.weight).sum()
+}
+
+/// True if `owner` can take on `extra` weight without going over their maximum load
+pub fn within_max_load<D>(
+    owner: Entity,
+    stats: &CombatStats,
+    extra: f32,
+    backpack: &Storage<InBackpack, D>,
+    items: &ReadStorage<Item>,
+) -> bool
+where
+    D: Deref<Target = MaskedStorage<InBackpack>>,
+{
+    carried_weight(owner, backpack, items) + extra <= carry_capacity(stats) * MAX_LOAD_MULTIPLIER
+}
+
+/// True if `owner` can add the item to what they carry. Anything without stats has no limit.
+pub fn can_carry(ecs: &World, owner: Entity, item: Entity) -> bool {
+    let items = ecs.read_storage::<Item>();
+    let backpack = ecs.read_storage::<InBackpack>();
+    match (ecs.read_storage::<CombatStats>().get(owner), items.get(item)) {
+        (Some(stats), Some(item)) => within_max_load(owner, stats, item.weight, &backpack, &items),
+        _ => true,
+    }
+}
+
+pub struct EncumbranceSystem {}
+
+impl<'a> System<'a> for EncumbranceSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, Entity>,
+        WriteExpect<'a, GameLog>,
+        ReadStorage<'a, CombatStats>,
+        WriteStorage<'a, MovementSpeed>,
+        ReadStorage<'a, InBackpack>,
+        ReadStorage<'a, Item>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, player_entity, mut log, combat_stats, mut movement_speed, backpack, items): Self::SystemData,
+    ) {
+        for (entity, stats, speed) in (&entities, &combat_stats, &mut movement_speed).join() {
+            let capacity = carry_capacity(stats);
+            let weight = carried_weight(entity, &backpack, &items);
+
+            // Every bit of capacity over the limit adds the same fraction to the move delay
+            let delay = if weight > capacity {
+                (speed.min_delay_ms as f32 * (weight - capacity) / capacity).ceil() as u64
+            } else {
+                0
+            };
+
+            if entity == *player_entity {
+                if speed.encumbrance_delay_ms == 0 && delay > 0 {
+                    log.add(LogCategory::Status, "You are overburdened and slow down.");
+                } else if speed.encumbrance_delay_ms > 0 && delay == 0 {
+                    log.add(LogCategory::Status, "You are no longer overburdened.");
+                }
+            }
+            speed.encumbrance_delay_ms = delay;
+        }
+    }
+}
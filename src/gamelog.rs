@@ -1,5 +1,179 @@
+use std::fmt;
+
 use bounded_vec_deque::BoundedVecDeque;
+use rltk::RGB;
+use serde::{Deserialize, Serialize};
+
+/// How many entries the log keeps before dropping the oldest
+pub const LOG_CAPACITY: usize = 500;
+/// How many of the most recent entries are written to a save
+pub const SAVED_LOG_ENTRIES: usize = 100;
+
+/// Colour for names of creatures and items in messages
+pub const NAME_COLOR: (u8, u8, u8) = rltk::YELLOW;
+/// Colour for amounts of damage and healing in messages
+pub const AMOUNT_COLOR: (u8, u8, u8) = rltk::ORANGE;
+
+/// What a message is about, so the log viewer can filter by it
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum LogCategory {
+    Combat,
+    Item,
+    /// Changes to the player's condition, like hunger or levelling up
+    Status,
+    /// Messages about the game itself rather than the dungeon
+    System,
+}
+
+impl LogCategory {
+    pub const ALL: [LogCategory; 4] =
+        [LogCategory::Combat, LogCategory::Item, LogCategory::Status, LogCategory::System];
+
+    /// Colour of the parts of a message that don't have one of their own
+    pub fn color(self) -> RGB {
+        match self {
+            LogCategory::Combat => RGB::named(rltk::PINK),
+            LogCategory::Item => RGB::named(rltk::LIGHT_BLUE),
+            LogCategory::Status => RGB::named(rltk::LIGHT_GREEN),
+            LogCategory::System => RGB::named(rltk::WHITE),
+        }
+    }
+}
+
+impl fmt::Display for LogCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Part of a message drawn in one colour, or in its category's colour if it has none
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct LogSegment {
+    pub text: String,
+    pub color: Option<RGB>,
+}
+
+/// A message built up from coloured segments
+#[derive(Default)]
+pub struct LogLine {
+    segments: Vec<LogSegment>,
+}
+
+impl LogLine {
+    pub fn new() -> Self {
+        LogLine::default()
+    }
+
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.segments.push(LogSegment { text: text.into(), color: None });
+        self
+    }
+
+    pub fn colored(mut self, text: impl Into<String>, color: (u8, u8, u8)) -> Self {
+        self.segments.push(LogSegment { text: text.into(), color: Some(RGB::named(color)) });
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    /// Turn the message was last logged on
+    pub turn: u64,
+    pub category: LogCategory,
+    pub segments: Vec<LogSegment>,
+    /// How many times in a row the message was logged
+    pub count: u32,
+}
 
 pub struct GameLog {
-    pub entries: BoundedVecDeque<String>,
+    pub entries: BoundedVecDeque<LogEntry>,
+    /// Turn that new messages are stamped with, kept up to date by the game loop
+    pub turn: u64,
+}
+
+impl GameLog {
+    pub fn new(capacity: usize) -> Self {
+        GameLog { entries: BoundedVecDeque::new(capacity), turn: 0 }
+    }
+
+    /// Logs a message in its category's colour
+    pub fn add(&mut self, category: LogCategory, text: impl Into<String>) {
+        self.add_line(category, LogLine::new().text(text));
+    }
+
+    /// Logs a message, or bumps the count of the last one if it's the same message again
+    pub fn add_line(&mut self, category: LogCategory, line: LogLine) {
+        if let Some(last) = self.entries.back_mut() {
+            if last.category == category && last.segments == line.segments {
+                last.count += 1;
+                last.turn = self.turn;
+                return;
+            }
+        }
+        self.entries.push_back(LogEntry { turn: self.turn, category, segments: line.segments, count: 1 });
+    }
+
+    /// Changes how many entries are kept, dropping the oldest if there are now too many
+    pub fn set_capacity(&mut self, capacity: usize) {
+        let excess = self.entries.len().saturating_sub(capacity);
+        self.entries.drain(..excess);
+        self.entries.set_max_len(capacity);
+    }
+
+    /// Up to `count` of the newest entries, oldest first
+    pub fn recent(&self, count: usize) -> Vec<LogEntry> {
+        self.entries.iter().skip(self.entries.len().saturating_sub(count)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_messages_are_merged() {
+        let mut log = GameLog::new(10);
+        log.add(LogCategory::Combat, "The orc misses you.");
+        log.turn = 3;
+        log.add(LogCategory::Combat, "The orc misses you.");
+        log.add(LogCategory::Combat, "The orc misses you.");
+        assert_eq!(log.entries.len(), 1);
+        assert_eq!(log.entries[0].count, 3);
+        assert_eq!(log.entries[0].turn, 3);
+    }
+
+    #[test]
+    fn different_messages_are_kept_apart() {
+        let mut log = GameLog::new(10);
+        log.add(LogCategory::Combat, "The orc misses you.");
+        log.add(LogCategory::System, "The orc misses you.");
+        log.add_line(LogCategory::Combat, LogLine::new().colored("The orc", NAME_COLOR).text(" misses you."));
+        log.add(LogCategory::Combat, "The orc misses you.");
+        assert_eq!(log.entries.len(), 4);
+        assert!(log.entries.iter().all(|entry| entry.count == 1));
+    }
+
+    #[test]
+    fn recent_keeps_the_newest_entries_in_order() {
+        let mut log = GameLog::new(10);
+        for i in 0..5 {
+            log.add(LogCategory::System, format!("Message {}", i));
+        }
+        let recent: Vec<String> = log.recent(3).iter().map(|entry| entry.segments[0].text.clone()).collect();
+        assert_eq!(recent, ["Message 2", "Message 3", "Message 4"]);
+        assert_eq!(log.recent(20).len(), 5);
+    }
+
+    #[test]
+    fn shrinking_drops_the_oldest_entries() {
+        let mut log = GameLog::new(10);
+        for i in 0..5 {
+            log.add(LogCategory::System, format!("Message {}", i));
+        }
+        log.set_capacity(2);
+        let kept: Vec<String> = log.entries.iter().map(|entry| entry.segments[0].text.clone()).collect();
+        assert_eq!(kept, ["Message 3", "Message 4"]);
+        log.add(LogCategory::System, "Message 5");
+        assert_eq!(log.entries.len(), 2);
+    }
 }
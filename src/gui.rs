@@ -1,14 +1,45 @@
-use rltk::{Point, RGB, Rltk};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use rltk::{DiceType, Point, Rltk, VirtualKeyCode, RGB};
+use serde::{Deserialize, Serialize};
 use specs::{Entity, Join, WorldExt};
 
-use crate::{CombatStats, Consumable, GameLog, InBackpack, Map, Name, Player, Position, RunState, State, VirtualKeyCode, WantsToDropItem, WantsToUseItem, World};
+use crate::command::Command;
+use crate::components::{
+    DamageType, Description, EntryTrigger, Experience, Gold, GrantsTelepathy, Hidden, HungerClock, HungerState,
+    Immobilized, InflictsDamage, Item, Locked, MagicMapper, MovementSpeed, NaturalAttack, ProvidesFood, Ranged,
+    Resistances, Telepathy, Vendor,
+};
+use crate::encumbrance_system::{carried_weight, carry_capacity};
+use crate::gamelog::{LogCategory, LogEntry};
+use crate::identification::Identification;
+use crate::inventory_system::{stacked_contents, stacked_inventory, throw_range, ItemStack};
+use crate::key_bindings::{Action, KeyBindings};
+use crate::melee_combat_system::armour_class;
+use crate::movement_util::GameClock;
+use crate::player::DIRECTIONS;
+use crate::settings::{
+    Font, Settings, KEY_REPEAT_DELAY_RANGE_MS, KEY_REPEAT_DELAY_STEP_MS, LOG_LENGTH_RANGE, LOG_LENGTH_STEP,
+};
+use crate::{
+    CombatStats, Consumable, GameLog, InBackpack, Map, Name, Player, Position, ProvidesHealing, RunState, State,
+    Viewshed, World,
+};
+use crate::shop;
 
 #[derive(PartialEq, Copy, Clone, Debug)]
-pub enum MainMenuSelection { NewGame, LoadGame, Quit }
+pub enum MainMenuSelection { NewGame, LoadGame, Options, Quit }
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum MainMenuResult { NoSelection{ selected : MainMenuSelection }, Selected{ selected: MainMenuSelection } }
 
+#[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum LevelUpSelection { Vitality, Might, Toughness }
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum LevelUpResult { NoSelection{ selected : LevelUpSelection }, Selected{ selected: LevelUpSelection } }
+
 pub fn draw_ui(gs: &mut State, ctx: &mut Rltk) {
     {
         let ecs = &mut gs.ecs;
@@ -23,15 +54,38 @@ pub fn draw_ui(gs: &mut State, ctx: &mut Rltk) {
             ctx.draw_bar_horizontal(28, 43, 51, stats.hp, stats.max_hp, RGB::named(rltk::RED), RGB::named(rltk::BLACK));
         }
 
-        let log = ecs.fetch::<GameLog>();
-        let mut y = 48;
-        for s in log.entries.iter().rev() {
-            if y >= 44 {
-                ctx.print(2, y, s);
-            } else {
-                break;
+        // Level and experience, on the bottom border of the panel
+        let experience = ecs.read_storage::<Experience>();
+        for (_player, exp) in (&players, &experience).join() {
+            let level = format!(" Level: {}  XP: {} / {} ", exp.level, exp.xp, exp.xp_to_next_level());
+            ctx.print_color(2, 49, RGB::named(rltk::GOLD), RGB::named(rltk::BLACK), &level);
+        }
+        let wallets = ecs.read_storage::<Gold>();
+        for (_player, wallet) in (&players, &wallets).join() {
+            let gold = format!(" Gold: {} ", wallet.amount);
+            ctx.print_color(77 - gold.len() as i32, 49, RGB::named(rltk::GOLD), RGB::named(rltk::BLACK), &gold);
+        }
+
+        // Hunger indicator, just above the HP bar
+        let hunger_clocks = ecs.read_storage::<HungerClock>();
+        for (_player, clock) in (&players, &hunger_clocks).join() {
+            match clock.state {
+                HungerState::WellFed => {
+                    ctx.print_color(71, 42, RGB::named(rltk::GREEN), RGB::named(rltk::BLACK), "Well Fed");
+                }
+                HungerState::Normal => {}
+                HungerState::Hungry => {
+                    ctx.print_color(71, 42, RGB::named(rltk::ORANGE), RGB::named(rltk::BLACK), "Hungry");
+                }
+                HungerState::Starving => {
+                    ctx.print_color(71, 42, RGB::named(rltk::RED), RGB::named(rltk::BLACK), "Starving");
+                }
             }
-            y -= 1;
+        }
+
+        let log = ecs.fetch::<GameLog>();
+        for (y, entry) in (44..49).rev().zip(log.entries.iter().rev()) {
+            print_log_entry(ctx, 2, y, entry);
         }
 
         // Mouse tooltip
@@ -40,86 +94,414 @@ pub fn draw_ui(gs: &mut State, ctx: &mut Rltk) {
 
         draw_tooltips(ecs, ctx);
     }
-
-    show_inventory(gs, ctx);
 }
 
-pub fn show_inventory(gs: &mut State, ctx: &mut Rltk) {
-    if gs.client.show_inventory {
-        let player_entity = gs.ecs.fetch::<Entity>();
-        let names = gs.ecs.read_storage::<Name>();
-        let backpack = gs.ecs.read_storage::<InBackpack>();
-        let entities = gs.ecs.entities();
+/// Rough kinds of item, used to filter and sort the inventory
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
+pub enum ItemCategory {
+    Potion,
+    Scroll,
+    Food,
+    Other,
+}
 
-        let inventory = (&backpack, &names).join().filter(|item| item.0.owner == *player_entity);
-        let count = inventory.count();
+impl ItemCategory {
+    const ALL: [ItemCategory; 4] =
+        [ItemCategory::Potion, ItemCategory::Scroll, ItemCategory::Food, ItemCategory::Other];
 
-        let mut y = (25 - (count / 2)) as i32;
-        ctx.draw_box(15, y - 2, 31, (count + 3) as i32, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK));
-        if gs.client.drop_inventory {
-            ctx.print_color(18, y - 2, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Drop Which Item?");
-        } else {
-            ctx.print_color(18, y - 2, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Inventory");
-        }
-        ctx.print_color(
-            18,
-            y + count as i32 + 1,
-            RGB::named(rltk::YELLOW),
-            RGB::named(rltk::BLACK),
-            "ESCAPE to close/cancel",
-        );
-
-        let mut equippable: Vec<Entity> = Vec::new();
-        let mut j = 0;
-        for (entity, _pack, name) in (&entities, &backpack, &names).join().filter(|item| item.1.owner == *player_entity)
+    pub fn of(ecs: &World, item: Entity) -> ItemCategory {
+        if ecs.read_storage::<ProvidesHealing>().contains(item) {
+            ItemCategory::Potion
+        } else if ecs.read_storage::<InflictsDamage>().contains(item)
+            || ecs.read_storage::<MagicMapper>().contains(item)
+            || ecs.read_storage::<GrantsTelepathy>().contains(item)
         {
-            ctx.set(17, y, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), rltk::to_cp437('('));
-            ctx.set(18, y, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), 97 + j as rltk::FontCharType);
-            ctx.set(19, y, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), rltk::to_cp437(')'));
+            ItemCategory::Scroll
+        } else if ecs.read_storage::<ProvidesFood>().contains(item) {
+            ItemCategory::Food
+        } else {
+            ItemCategory::Other
+        }
+    }
+}
 
-            ctx.print(21, y, &name.name.to_string());
-            equippable.push(entity);
-            y += 1;
-            j += 1;
+impl Display for ItemCategory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ItemCategory::Potion => "Potions",
+            ItemCategory::Scroll => "Scrolls",
+            ItemCategory::Food => "Food",
+            ItemCategory::Other => "Other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum InventorySort {
+    /// The order things were picked up in
+    #[default]
+    Acquired,
+    Name,
+    Category,
+    Weight,
+}
+
+impl InventorySort {
+    fn next(self) -> InventorySort {
+        match self {
+            InventorySort::Acquired => InventorySort::Name,
+            InventorySort::Name => InventorySort::Category,
+            InventorySort::Category => InventorySort::Weight,
+            InventorySort::Weight => InventorySort::Acquired,
         }
+    }
+}
 
-        match ctx.key {
-            Some(key) => {
-                let selection = rltk::letter_to_option(key);
-                if selection > -1 && selection < count as i32 {
-                    let selection = selection as usize;
-
-                    if gs.client.drop_inventory {
-                        // Drop items
-                        let mut drop_intents = gs.ecs.write_storage::<WantsToDropItem>();
-                        drop_intents
-                            .insert(
-                                *player_entity,
-                                WantsToDropItem {
-                                    item: equippable[selection],
-                                },
-                            )
-                            .expect("Unable to insert item to drop");
-                    } else {
-                        // Use Items
-                        let consumables = gs.ecs.read_storage::<Consumable>();
-                        if consumables.contains(equippable[selection]) {
-                            let mut use_item = gs.ecs.write_storage::<WantsToUseItem>();
-                            use_item
-                                .insert(
-                                    *player_entity,
-                                    WantsToUseItem {
-                                        item: equippable[selection],
-                                    },
-                                )
-                                .expect("Unable to insert drink potion intent");
-                        }
+/// What selecting an item in the inventory does
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum InventoryMode {
+    #[default]
+    Use,
+    Drop,
+    Throw,
+}
+
+impl InventoryMode {
+    /// Switches to a mode, or back to using items if already in it
+    pub fn toggle(&mut self, mode: InventoryMode) {
+        *self = if *self == mode { InventoryMode::Use } else { mode };
+    }
+}
+
+/// Client-side state of the inventory window, kept between ticks
+#[derive(Debug, Default)]
+pub struct InventoryMenu {
+    pub mode: InventoryMode,
+    /// Index of the highlighted entry among the filtered entries
+    pub cursor: usize,
+    pub sort: InventorySort,
+    /// Only show this category, or everything if None
+    pub filter: Option<ItemCategory>,
+    /// Tile picked from the context menu to throw the chosen item at, skipping targeting
+    pub target: Option<Point>,
+}
+
+impl InventoryMenu {
+    /// Goes back to using items, as when the menu is closed
+    pub fn reset_mode(&mut self) {
+        self.mode = InventoryMode::Use;
+        self.target = None;
+    }
+
+    fn next_filter(&mut self, forwards: bool) {
+        let mut filters: Vec<Option<ItemCategory>> = vec![None];
+        filters.extend(ItemCategory::ALL.iter().copied().map(Some));
+        let current = filters.iter().position(|f| *f == self.filter).unwrap_or(0);
+        let next = if forwards { current + 1 } else { current + filters.len() - 1 };
+        self.filter = filters[next % filters.len()];
+        self.cursor = 0;
+    }
+}
+
+/// Entries shown per page, which must stay within the selection letters left once inventory actions
+/// have taken theirs
+const INVENTORY_PAGE_SIZE: usize = 15;
+/// Actions that still work with the inventory open, so their keys can't select items as well
+const INVENTORY_ACTIONS: [Action; 4] = [Action::ToggleInventory, Action::DropMode, Action::ThrowMode, Action::Cancel];
+const INVENTORY_X: i32 = 10;
+const INVENTORY_Y: i32 = 4;
+const INVENTORY_WIDTH: i32 = 52;
+const INVENTORY_LIST_Y: i32 = INVENTORY_Y + 3;
+const INVENTORY_DETAILS_Y: i32 = INVENTORY_LIST_Y + INVENTORY_PAGE_SIZE as i32 + 2;
+
+/// Letters that select the entries on a page in order, skipping any bound to an inventory action
+fn page_letters(keys: &KeyBindings) -> Vec<(VirtualKeyCode, char)> {
+    use VirtualKeyCode::*;
+
+    let letter_keys = [A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z];
+    letter_keys
+        .into_iter()
+        .zip('a'..='z')
+        .filter(|(key, _)| !keys.action_for(*key).is_some_and(|action| INVENTORY_ACTIONS.contains(&action)))
+        .take(INVENTORY_PAGE_SIZE)
+        .collect()
+}
+
+struct InventoryEntry {
+    stack: ItemStack,
+    category: ItemCategory,
+    weight: f32,
+}
+
+fn inventory_entries(ecs: &World, owner: Entity, menu: &InventoryMenu) -> Vec<InventoryEntry> {
+    let items = ecs.read_storage::<Item>();
+    let mut entries: Vec<InventoryEntry> = stacked_inventory(ecs, owner)
+        .into_iter()
+        .map(|stack| {
+            let category = ItemCategory::of(ecs, stack.items[0]);
+            let weight = items.get(stack.items[0]).map_or(0.0, |item| item.weight);
+            InventoryEntry { stack, category, weight }
+        })
+        .filter(|entry| menu.filter.is_none_or(|filter| filter == entry.category))
+        .collect();
+
+    match menu.sort {
+        InventorySort::Acquired => {}
+        InventorySort::Name => entries.sort_by(|a, b| a.stack.name.cmp(&b.stack.name)),
+        InventorySort::Category => {
+            entries.sort_by(|a, b| a.category.cmp(&b.category).then_with(|| a.stack.name.cmp(&b.stack.name)))
+        }
+        InventorySort::Weight => entries.sort_by(|a, b| b.weight.total_cmp(&a.weight)),
+    }
+    entries
+}
+
+/// Describes what the highlighted item does
+fn item_details(ecs: &World, entry: &InventoryEntry) -> Vec<String> {
+    let item = entry.stack.items[0];
+    let mut details = vec![format!(
+        "Weight: {:.1} lbs each, {:.1} lbs total",
+        entry.weight,
+        entry.weight * entry.stack.items.len() as f32
+    )];
+    details.extend(item_effects(ecs, item, &entry.stack.real_name));
+    details
+}
+
+/// What an item does, as far as the player knows
+fn item_effects(ecs: &World, item: Entity, real_name: &str) -> Vec<String> {
+    let mut details = Vec::new();
+    if !ecs.fetch::<Identification>().is_identified(real_name) {
+        details.push("You don't know what this does yet".to_string());
+        return details;
+    }
+    if let Some(healing) = ecs.read_storage::<ProvidesHealing>().get(item) {
+        details.push(format!("Restores {} HP", healing.heal_amount));
+    }
+    if let Some(damage) = ecs.read_storage::<InflictsDamage>().get(item) {
+        details.push(format!("Deals {} {} damage", damage.damage, damage.damage_type));
+    }
+    if let Some(ranged) = ecs.read_storage::<Ranged>().get(item) {
+        details.push(format!("Range: {} tiles", ranged.range));
+    }
+    if ecs.read_storage::<ProvidesFood>().contains(item) {
+        details.push("Cures hunger".to_string());
+    }
+    if ecs.read_storage::<MagicMapper>().contains(item) {
+        details.push("Reveals the whole level".to_string());
+    }
+    if let Some(grant) = ecs.read_storage::<GrantsTelepathy>().get(item) {
+        details.push(format!("Senses monsters for {} turns", grant.turns));
+    }
+    details
+}
+
+/// Draws the inventory if it is open, returning the run state to switch to if the chosen item
+/// needs a target
+pub fn show_inventory(gs: &mut State, ctx: &mut Rltk) -> Option<RunState> {
+    if !gs.client.show_inventory {
+        return None;
+    }
+
+    let player_entity = *gs.ecs.fetch::<Entity>();
+    let letters = page_letters(&gs.client.keys);
+    let menu = &mut gs.client.inventory;
+    let entries = inventory_entries(&gs.ecs, player_entity, menu);
+    let pages = usize::max(1, entries.len().div_ceil(INVENTORY_PAGE_SIZE));
+
+    // Navigation
+    menu.cursor = menu.cursor.min(entries.len().saturating_sub(1));
+    let mut selection: Option<usize> = None;
+    if let Some(key) = ctx.key {
+        match key {
+            VirtualKeyCode::Up => menu.cursor = menu.cursor.saturating_sub(1),
+            VirtualKeyCode::Down => menu.cursor = usize::min(menu.cursor + 1, entries.len().saturating_sub(1)),
+            VirtualKeyCode::PageUp => menu.cursor = menu.cursor.saturating_sub(INVENTORY_PAGE_SIZE),
+            VirtualKeyCode::PageDown => {
+                menu.cursor = usize::min(menu.cursor + INVENTORY_PAGE_SIZE, entries.len().saturating_sub(1))
+            }
+            VirtualKeyCode::Left => menu.next_filter(false),
+            VirtualKeyCode::Right => menu.next_filter(true),
+            VirtualKeyCode::Tab => menu.sort = menu.sort.next(),
+            VirtualKeyCode::Return if !entries.is_empty() => selection = Some(menu.cursor),
+            _ => {
+                // Letters pick from the current page
+                if let Some(row) = letters.iter().position(|(letter_key, _)| *letter_key == key) {
+                    let index = (menu.cursor / INVENTORY_PAGE_SIZE) * INVENTORY_PAGE_SIZE + row;
+                    if index < entries.len() {
+                        selection = Some(index);
                     }
                 }
             }
-            None => {}
         }
     }
+    // Filtering or sorting may have changed what is shown
+    let entries = inventory_entries(&gs.ecs, player_entity, menu);
+    let page = menu.cursor / INVENTORY_PAGE_SIZE;
+    let page_start = page * INVENTORY_PAGE_SIZE;
+
+    // Mouse hover highlights, clicking selects
+    let (mouse_x, mouse_y) = ctx.mouse_pos();
+    let mouse_row = mouse_y - INVENTORY_LIST_Y;
+    let in_list = mouse_x > INVENTORY_X && mouse_x < INVENTORY_X + INVENTORY_WIDTH;
+    if in_list && (0..INVENTORY_PAGE_SIZE as i32).contains(&mouse_row) {
+        let index = page_start + mouse_row as usize;
+        if index < entries.len() {
+            menu.cursor = index;
+            if ctx.left_click {
+                selection = Some(index);
+            }
+        }
+    }
+
+    // Window
+    let white = RGB::named(rltk::WHITE);
+    let yellow = RGB::named(rltk::YELLOW);
+    let gray = RGB::named(rltk::GRAY);
+    let black = RGB::named(rltk::BLACK);
+    ctx.draw_box(INVENTORY_X, INVENTORY_Y, INVENTORY_WIDTH, 28, white, black);
+    let title = match menu.mode {
+        InventoryMode::Use => "Inventory",
+        InventoryMode::Drop => "Drop Which Item?",
+        InventoryMode::Throw => "Throw Which Item?",
+    };
+    ctx.print_color(INVENTORY_X + 3, INVENTORY_Y, yellow, black, title);
+    {
+        let backpack = gs.ecs.read_storage::<InBackpack>();
+        let items = gs.ecs.read_storage::<Item>();
+        if let Some(stats) = gs.ecs.read_storage::<CombatStats>().get(player_entity) {
+            let weight =
+                format!("{:.1}/{:.0} lbs", carried_weight(player_entity, &backpack, &items), carry_capacity(stats));
+            ctx.print_color(INVENTORY_X + INVENTORY_WIDTH - 2 - weight.len() as i32, INVENTORY_Y, gray, black, &weight);
+        }
+    }
+    let filter = menu.filter.map_or("All".to_string(), |f| f.to_string());
+    ctx.print_color(
+        INVENTORY_X + 2,
+        INVENTORY_Y + 1,
+        gray,
+        black,
+        format!("Showing: {}   Sorted by: {:?}", filter, menu.sort),
+    );
+
+    // Entries on this page. Using or dropping a stack only ever takes its first item.
+    if entries.is_empty() {
+        ctx.print_color(INVENTORY_X + 2, INVENTORY_LIST_Y, gray, black, "Nothing here.");
+    }
+    for (j, entry) in entries.iter().skip(page_start).take(INVENTORY_PAGE_SIZE).enumerate() {
+        let y = INVENTORY_LIST_Y + j as i32;
+        let fg = if page_start + j == menu.cursor { RGB::named(rltk::MAGENTA) } else { white };
+        ctx.set(INVENTORY_X + 2, y, white, black, rltk::to_cp437('('));
+        let letter = letters.get(j).map_or(' ', |(_, letter)| *letter);
+        ctx.set(INVENTORY_X + 3, y, yellow, black, rltk::to_cp437(letter));
+        ctx.set(INVENTORY_X + 4, y, white, black, rltk::to_cp437(')'));
+        let label = match entry.stack.items.len() {
+            1 => entry.stack.name.clone(),
+            n => format!("{} (x{})", entry.stack.name, n),
+        };
+        ctx.print_color(INVENTORY_X + 6, y, fg, black, label);
+    }
+    if pages > 1 {
+        let page_text = format!("Page {}/{}", page + 1, pages);
+        let y = INVENTORY_LIST_Y + INVENTORY_PAGE_SIZE as i32;
+        ctx.print_color(INVENTORY_X + INVENTORY_WIDTH - 2 - page_text.len() as i32, y, gray, black, page_text);
+    }
+
+    // Details of the highlighted entry
+    if let Some(entry) = entries.get(menu.cursor) {
+        ctx.print_color(INVENTORY_X + 2, INVENTORY_DETAILS_Y, yellow, black, &entry.stack.name);
+        for (i, line) in item_details(&gs.ecs, entry).iter().enumerate() {
+            ctx.print(INVENTORY_X + 4, INVENTORY_DETAILS_Y + 1 + i as i32, line);
+        }
+    }
+
+    ctx.print_color(INVENTORY_X + 2, INVENTORY_Y + 25, gray, black, "ENTER/letter/click select  G drop  T throw");
+    ctx.print_color(INVENTORY_X + 2, INVENTORY_Y + 26, gray, black, "PGUP/PGDN page  LEFT/RIGHT filter  TAB sort");
+    ctx.print_color(INVENTORY_X + 2, INVENTORY_Y + 27, gray, black, "ESCAPE close");
+
+    let item = entries.get(selection?)?.stack.items[0];
+    match menu.mode {
+        InventoryMode::Drop => {
+            gs.perform(Command::DropItem { item: item.id() });
+        }
+        InventoryMode::Throw => {
+            gs.client.show_inventory = false;
+            if let Some(target) = menu.target.take() {
+                menu.mode = InventoryMode::Use;
+                gs.perform(Command::ThrowItem { item: item.id(), target });
+                return None;
+            }
+            let range = gs.ecs.read_storage::<CombatStats>().get(player_entity).map_or(1, throw_range);
+            return Some(RunState::ShowTargeting { range, item, action: TargetAction::Throw });
+        }
+        InventoryMode::Use => {
+            // Ranged items need a target picking first
+            if let Some(ranged) = gs.ecs.read_storage::<Ranged>().get(item) {
+                gs.client.show_inventory = false;
+                return Some(RunState::ShowTargeting { range: ranged.range, item, action: TargetAction::Use });
+            }
+            if gs.ecs.read_storage::<Consumable>().contains(item) {
+                gs.perform(Command::UseItem { item: item.id(), target: None });
+            }
+        }
+    }
+    None
+}
+
+/// What to do with an item once a target tile has been picked
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum TargetAction {
+    Use,
+    Throw,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum TargetingResult {
+    Cancel,
+    NoResponse,
+    Selected(Point),
+}
+
+/// Highlights the visible tiles within range and lets the player click one of them
+pub fn ranged_target(gs: &mut State, ctx: &mut Rltk, range: i32) -> TargetingResult {
+    let player_entity = gs.ecs.fetch::<Entity>();
+    let player_pos = gs.ecs.fetch::<Point>();
+    let viewsheds = gs.ecs.read_storage::<Viewshed>();
+
+    ctx.print_color(5, 0, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Select Target (ESCAPE to cancel):");
+
+    let Some(viewshed) = viewsheds.get(*player_entity) else {
+        return TargetingResult::Cancel;
+    };
+    let mut available: Vec<Point> = Vec::new();
+    for tile in viewshed.visible_tiles.iter() {
+        let distance = rltk::DistanceAlg::Pythagoras.distance2d(*player_pos, *tile);
+        if distance <= range as f32 {
+            ctx.set_bg(tile.x, tile.y, RGB::named(rltk::BLUE));
+            available.push(*tile);
+        }
+    }
+
+    let (mouse_x, mouse_y) = ctx.mouse_pos();
+    let mouse = Point::new(mouse_x, mouse_y);
+    if available.contains(&mouse) {
+        ctx.set_bg(mouse.x, mouse.y, RGB::named(rltk::CYAN));
+        if ctx.left_click {
+            return TargetingResult::Selected(mouse);
+        }
+    } else {
+        ctx.set_bg(mouse.x, mouse.y, RGB::named(rltk::RED));
+        if ctx.left_click {
+            return TargetingResult::Cancel;
+        }
+    }
+
+    // Goes through the key's repeat delay so the same press doesn't also open the save menu
+    if gs.client.keys.check(Action::Cancel) {
+        return TargetingResult::Cancel;
+    }
+    TargetingResult::NoResponse
 }
 
 pub fn main_menu(gs : &mut State, ctx : &mut Rltk) -> MainMenuResult {
@@ -128,22 +510,56 @@ pub fn main_menu(gs : &mut State, ctx : &mut Rltk) -> MainMenuResult {
     ctx.print_color_centered(15, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Rust Roguelike Tutorial");
 
     if let RunState::MainMenu{ menu_selection : selection } = *runstate {
+        // Loading is skipped over when there's nothing to load
+        let save_exists = gs.client.save_exists;
+        let selection = match selection {
+            MainMenuSelection::LoadGame if !save_exists => MainMenuSelection::NewGame,
+            selection => selection,
+        };
+
         if selection == MainMenuSelection::NewGame {
             ctx.print_color_centered(24, RGB::named(rltk::MAGENTA), RGB::named(rltk::BLACK), "Begin New Game");
         } else {
             ctx.print_color_centered(24, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), "Begin New Game");
         }
 
-        if selection == MainMenuSelection::LoadGame {
+        if !save_exists {
+            ctx.print_color_centered(25, RGB::named(rltk::GREY), RGB::named(rltk::BLACK), "Load Game");
+        } else if selection == MainMenuSelection::LoadGame {
             ctx.print_color_centered(25, RGB::named(rltk::MAGENTA), RGB::named(rltk::BLACK), "Load Game");
         } else {
             ctx.print_color_centered(25, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), "Load Game");
         }
+        if let Some(info) = &gs.client.save_info {
+            let summary = format!("level {}, turn {}, {}", info.level, info.turn, info.age());
+            ctx.print_color(46, 25, RGB::named(rltk::GREY), RGB::named(rltk::BLACK), summary);
+        }
+
+        if selection == MainMenuSelection::Options {
+            ctx.print_color_centered(26, RGB::named(rltk::MAGENTA), RGB::named(rltk::BLACK), "Options");
+        } else {
+            ctx.print_color_centered(26, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), "Options");
+        }
 
         if selection == MainMenuSelection::Quit {
-            ctx.print_color_centered(26, RGB::named(rltk::MAGENTA), RGB::named(rltk::BLACK), "Quit");
+            ctx.print_color_centered(27, RGB::named(rltk::MAGENTA), RGB::named(rltk::BLACK), "Quit");
         } else {
-            ctx.print_color_centered(26, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), "Quit");
+            ctx.print_color_centered(27, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), "Quit");
+        }
+
+        // Clicking an option chooses it
+        let options = [
+            (24, MainMenuSelection::NewGame),
+            (25, MainMenuSelection::LoadGame),
+            (26, MainMenuSelection::Options),
+            (27, MainMenuSelection::Quit),
+        ];
+        let (_, mouse_y) = ctx.mouse_pos();
+        let available = |option: &MainMenuSelection| save_exists || *option != MainMenuSelection::LoadGame;
+        if let Some((_, option)) = options.iter().find(|(y, option)| *y == mouse_y && available(option)) {
+            if ctx.left_click {
+                return MainMenuResult::Selected{ selected: *option };
+            }
         }
 
         match ctx.key {
@@ -152,21 +568,23 @@ pub fn main_menu(gs : &mut State, ctx : &mut Rltk) -> MainMenuResult {
                 match key {
                     VirtualKeyCode::Escape => { return MainMenuResult::NoSelection{ selected: MainMenuSelection::Quit } }
                     VirtualKeyCode::Up => {
-                        let newselection;
-                        match selection {
-                            MainMenuSelection::NewGame => newselection = MainMenuSelection::Quit,
-                            MainMenuSelection::LoadGame => newselection = MainMenuSelection::NewGame,
-                            MainMenuSelection::Quit => newselection = MainMenuSelection::LoadGame
-                        }
+                        let newselection = match selection {
+                            MainMenuSelection::NewGame => MainMenuSelection::Quit,
+                            MainMenuSelection::LoadGame => MainMenuSelection::NewGame,
+                            MainMenuSelection::Options if save_exists => MainMenuSelection::LoadGame,
+                            MainMenuSelection::Options => MainMenuSelection::NewGame,
+                            MainMenuSelection::Quit => MainMenuSelection::Options
+                        };
                         return MainMenuResult::NoSelection{ selected: newselection }
                     }
                     VirtualKeyCode::Down => {
-                        let newselection;
-                        match selection {
-                            MainMenuSelection::NewGame => newselection = MainMenuSelection::LoadGame,
-                            MainMenuSelection::LoadGame => newselection = MainMenuSelection::Quit,
-                            MainMenuSelection::Quit => newselection = MainMenuSelection::NewGame
-                        }
+                        let newselection = match selection {
+                            MainMenuSelection::NewGame if save_exists => MainMenuSelection::LoadGame,
+                            MainMenuSelection::NewGame => MainMenuSelection::Options,
+                            MainMenuSelection::LoadGame => MainMenuSelection::Options,
+                            MainMenuSelection::Options => MainMenuSelection::Quit,
+                            MainMenuSelection::Quit => MainMenuSelection::NewGame
+                        };
                         return MainMenuResult::NoSelection{ selected: newselection }
                     }
                     VirtualKeyCode::Return => return MainMenuResult::Selected{ selected : selection },
@@ -179,10 +597,84 @@ pub fn main_menu(gs : &mut State, ctx : &mut Rltk) -> MainMenuResult {
     MainMenuResult::NoSelection { selected: MainMenuSelection::NewGame }
 }
 
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ConfirmResult {
+    Yes,
+    No,
+    NoResponse,
+}
+
+/// Asks before starting a new game, since saving it will overwrite the existing save
+pub fn confirm_new_game(ctx: &mut Rltk) -> ConfirmResult {
+    let white = RGB::named(rltk::WHITE);
+    let black = RGB::named(rltk::BLACK);
+    ctx.draw_box(17, 20, 45, 6, white, black);
+    ctx.print_color_centered(20, RGB::named(rltk::YELLOW), black, "Start a new game?");
+    ctx.print_color_centered(22, white, black, "There is already a saved game, which will be");
+    ctx.print_color_centered(23, white, black, "overwritten when the new game is saved.");
+    ctx.print_color_centered(25, white, black, "Y to continue, N to go back");
+
+    match ctx.key {
+        Some(VirtualKeyCode::Y) => ConfirmResult::Yes,
+        Some(VirtualKeyCode::N) | Some(VirtualKeyCode::Escape) => ConfirmResult::No,
+        _ => ConfirmResult::NoResponse,
+    }
+}
+
+pub fn level_up_menu(gs : &mut State, ctx : &mut Rltk) -> LevelUpResult {
+    let runstate = gs.ecs.fetch::<RunState>();
+
+    if let RunState::LevelUp{ menu_selection : selection, .. } = *runstate {
+        ctx.draw_box(20, 18, 39, 8, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK));
+        ctx.print_color_centered(18, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Level Up! Choose a bonus");
+
+        let options = [
+            (LevelUpSelection::Vitality, "Vitality: +10 max HP"),
+            (LevelUpSelection::Might, "Might: +1 power"),
+            (LevelUpSelection::Toughness, "Toughness: +1 defense"),
+        ];
+        for (i, (option, text)) in options.iter().enumerate() {
+            let fg = if *option == selection { RGB::named(rltk::MAGENTA) } else { RGB::named(rltk::WHITE) };
+            ctx.print_color_centered(21 + i as i32, fg, RGB::named(rltk::BLACK), text);
+        }
+        ctx.print_color_centered(26, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "ENTER to choose");
+
+        match ctx.key {
+            None => return LevelUpResult::NoSelection{ selected: selection },
+            Some(key) => {
+                match key {
+                    VirtualKeyCode::Up => {
+                        let newselection = match selection {
+                            LevelUpSelection::Vitality => LevelUpSelection::Toughness,
+                            LevelUpSelection::Might => LevelUpSelection::Vitality,
+                            LevelUpSelection::Toughness => LevelUpSelection::Might
+                        };
+                        return LevelUpResult::NoSelection{ selected: newselection }
+                    }
+                    VirtualKeyCode::Down => {
+                        let newselection = match selection {
+                            LevelUpSelection::Vitality => LevelUpSelection::Might,
+                            LevelUpSelection::Might => LevelUpSelection::Toughness,
+                            LevelUpSelection::Toughness => LevelUpSelection::Vitality
+                        };
+                        return LevelUpResult::NoSelection{ selected: newselection }
+                    }
+                    VirtualKeyCode::Return => return LevelUpResult::Selected{ selected : selection },
+                    _ => return LevelUpResult::NoSelection{ selected: selection }
+                }
+            }
+        }
+    }
+
+    LevelUpResult::NoSelection { selected: LevelUpSelection::Vitality }
+}
+
 fn draw_tooltips(ecs: &World, ctx: &mut Rltk) {
     let map = ecs.fetch::<Map>();
     let names = ecs.read_storage::<Name>();
     let positions = ecs.read_storage::<Position>();
+    let identification = ecs.fetch::<Identification>();
+    let hidden = ecs.read_storage::<Hidden>();
 
     let (mouse_x, mouse_y) = ctx.mouse_pos();
     if mouse_x >= map.width || mouse_y >= map.height {
@@ -190,10 +682,10 @@ fn draw_tooltips(ecs: &World, ctx: &mut Rltk) {
     }
 
     let mut tooltip: Vec<String> = Vec::new();
-    for (name, position) in (&names, &positions).join() {
+    for (name, position, _hidden) in (&names, &positions, !&hidden).join() {
         let idx = map.xy_idx(position.x, position.y);
         if position.x == mouse_x && position.y == mouse_y && map.visible_tiles[idx] {
-            tooltip.push(name.name.to_string());
+            tooltip.push(identification.name_of(&name.name));
         }
     }
 
@@ -209,8 +701,8 @@ fn draw_tooltips(ecs: &World, ctx: &mut Rltk) {
         if mouse_x > 40 {
             let arrow_pos = Point::new(mouse_x - 2, mouse_y);
             let left_x = mouse_x - width;
-            let mut y = mouse_y;
-            for s in tooltip.iter() {
+            for (i, s) in tooltip.iter().enumerate() {
+                let y = mouse_y + i as i32;
                 ctx.print_color(left_x, y, RGB::named(rltk::WHITE), RGB::named(rltk::GREY), s);
                 let padding = (width - s.len() as i32) - 1;
                 for i in 0..padding {
@@ -219,23 +711,22 @@ fn draw_tooltips(ecs: &World, ctx: &mut Rltk) {
                         y,
                         RGB::named(rltk::WHITE),
                         RGB::named(rltk::GREY),
-                        &" ".to_string(),
+                        " ",
                     );
                 }
-                y += 1;
             }
             ctx.print_color(
                 arrow_pos.x,
                 arrow_pos.y,
                 RGB::named(rltk::WHITE),
                 RGB::named(rltk::GREY),
-                &"->".to_string(),
+                "->",
             );
         } else {
             let arrow_pos = Point::new(mouse_x + 1, mouse_y);
             let left_x = mouse_x + 3;
-            let mut y = mouse_y;
-            for s in tooltip.iter() {
+            for (i, s) in tooltip.iter().enumerate() {
+                let y = mouse_y + i as i32;
                 ctx.print_color(left_x + 1, y, RGB::named(rltk::WHITE), RGB::named(rltk::GREY), s);
                 let padding = (width - s.len() as i32) - 1;
                 for i in 0..padding {
@@ -244,18 +735,700 @@ fn draw_tooltips(ecs: &World, ctx: &mut Rltk) {
                         y,
                         RGB::named(rltk::WHITE),
                         RGB::named(rltk::GREY),
-                        &" ".to_string(),
+                        " ",
                     );
                 }
-                y += 1;
             }
             ctx.print_color(
                 arrow_pos.x,
                 arrow_pos.y,
                 RGB::named(rltk::WHITE),
                 RGB::named(rltk::GREY),
-                &"<-".to_string(),
+                "<-",
             );
         }
     }
 }
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ShopMode {
+    Buy,
+    Sell,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ShopResult {
+    Close,
+    NoSelection { mode: ShopMode, selection: usize },
+    Selected { mode: ShopMode, selection: usize, item: Entity },
+}
+
+/// Rows of stock shown at once before the list scrolls
+const SHOP_ROWS: usize = 20;
+
+/// Buy/sell screen for trading with a vendor. Buying lists the vendor's backpack and selling lists
+/// the player's, each with its price.
+pub fn show_shop(gs: &mut State, ctx: &mut Rltk, vendor: Entity, mode: ShopMode, selection: usize) -> ShopResult {
+    let player_entity = *gs.ecs.fetch::<Entity>();
+    let seller = if mode == ShopMode::Buy { vendor } else { player_entity };
+    let stacks = stacked_inventory(&gs.ecs, seller);
+    let selection = selection.min(stacks.len().saturating_sub(1));
+
+    let white = RGB::named(rltk::WHITE);
+    let yellow = RGB::named(rltk::YELLOW);
+    let gray = RGB::named(rltk::GRAY);
+    let black = RGB::named(rltk::BLACK);
+    {
+        let items = gs.ecs.read_storage::<Item>();
+        let wallets = gs.ecs.read_storage::<Gold>();
+        let vendor_name = gs.ecs.read_storage::<Name>().get(vendor).unwrap().name.clone();
+        let gold_of = |entity| wallets.get(entity).map_or(0, |wallet| wallet.amount);
+
+        ctx.draw_box(10, 8, 52, SHOP_ROWS as i32 + 5, white, black);
+        let title = match mode {
+            ShopMode::Buy => format!("Buying from {}", vendor_name),
+            ShopMode::Sell => format!("Selling to {}", vendor_name),
+        };
+        ctx.print_color(13, 8, yellow, black, title);
+        let wallets_text =
+            format!("Your gold: {}   {}'s gold: {}", gold_of(player_entity), vendor_name, gold_of(vendor));
+        ctx.print_color(12, 9, RGB::named(rltk::GOLD), black, wallets_text);
+
+        if stacks.is_empty() {
+            ctx.print_color(12, 11, gray, black, "Nothing to trade.");
+        }
+        let offset = (selection + 1).saturating_sub(SHOP_ROWS);
+        for (j, stack) in stacks.iter().enumerate().skip(offset).take(SHOP_ROWS) {
+            let y = 11 + (j - offset) as i32;
+            let item = items.get(stack.items[0]);
+            let price = match mode {
+                ShopMode::Buy => item.map_or(0, shop::buy_price),
+                ShopMode::Sell => item.map_or(0, shop::sell_price),
+            };
+            // Grey out whatever the buyer can't pay for
+            let buyer = if mode == ShopMode::Buy { player_entity } else { vendor };
+            let fg = if j == selection {
+                RGB::named(rltk::MAGENTA)
+            } else if gold_of(buyer) < price {
+                gray
+            } else {
+                white
+            };
+            let label = match stack.items.len() {
+                1 => stack.name.clone(),
+                n => format!("{} (x{})", stack.name, n),
+            };
+            ctx.print_color(12, y, fg, black, label);
+            let price_text = format!("{} gp", price);
+            ctx.print_color(60 - price_text.len() as i32, y, fg, black, price_text);
+        }
+
+        let action = if mode == ShopMode::Buy { "buy" } else { "sell" };
+        let other_mode = if mode == ShopMode::Buy { "sell" } else { "buy" };
+        let help = format!("ENTER {}  TAB {}  ESCAPE leave", action, other_mode);
+        ctx.print_color(12, 12 + SHOP_ROWS as i32, gray, black, help);
+    }
+
+    // Goes through the key's repeat delay so the same press doesn't also open the save menu
+    if gs.client.keys.check(Action::Cancel) {
+        return ShopResult::Close;
+    }
+    match ctx.key {
+        Some(VirtualKeyCode::Up) => ShopResult::NoSelection { mode, selection: selection.saturating_sub(1) },
+        Some(VirtualKeyCode::Down) => {
+            ShopResult::NoSelection { mode, selection: usize::min(selection + 1, stacks.len().saturating_sub(1)) }
+        }
+        Some(VirtualKeyCode::Tab) => {
+            let mode = if mode == ShopMode::Buy { ShopMode::Sell } else { ShopMode::Buy };
+            ShopResult::NoSelection { mode, selection: 0 }
+        }
+        Some(VirtualKeyCode::Return) if !stacks.is_empty() => {
+            ShopResult::Selected { mode, selection, item: stacks[selection].items[0] }
+        }
+        _ => ShopResult::NoSelection { mode, selection },
+    }
+}
+
+/// Which side of the loot screen the selection is on
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum LootSide {
+    Container,
+    Backpack,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum LootResult {
+    Close,
+    NoSelection { side: LootSide, selection: usize },
+    Selected { side: LootSide, selection: usize, item: Entity },
+}
+
+/// Shows a container's contents next to the player's backpack, so items can be moved either way
+pub fn show_container(
+    gs: &mut State,
+    ctx: &mut Rltk,
+    container: Entity,
+    side: LootSide,
+    selection: usize,
+) -> LootResult {
+    let player_entity = *gs.ecs.fetch::<Entity>();
+    let contents = stacked_contents(&gs.ecs, container);
+    let backpack = stacked_inventory(&gs.ecs, player_entity);
+    let selected = if side == LootSide::Container { &contents } else { &backpack };
+    let selection = selection.min(selected.len().saturating_sub(1));
+
+    let white = RGB::named(rltk::WHITE);
+    let yellow = RGB::named(rltk::YELLOW);
+    let gray = RGB::named(rltk::GRAY);
+    let black = RGB::named(rltk::BLACK);
+    let container_name = gs.ecs.read_storage::<Name>().get(container).unwrap().name.clone();
+
+    ctx.draw_box(5, 8, 70, SHOP_ROWS as i32 + 5, white, black);
+    ctx.print_color(8, 8, yellow, black, format!("Looting the {}", container_name));
+    let columns =
+        [(LootSide::Container, 7, container_name.as_str(), &contents), (LootSide::Backpack, 41, "Backpack", &backpack)];
+    for (column_side, x, title, stacks) in columns {
+        ctx.print_color(x, 10, yellow, black, title);
+        if stacks.is_empty() {
+            ctx.print_color(x, 11, gray, black, "Empty");
+        }
+        let offset = if column_side == side { (selection + 1).saturating_sub(SHOP_ROWS) } else { 0 };
+        for (j, stack) in stacks.iter().enumerate().skip(offset).take(SHOP_ROWS) {
+            let fg = if column_side == side && j == selection { RGB::named(rltk::MAGENTA) } else { white };
+            let mut label = match stack.items.len() {
+                1 => stack.name.clone(),
+                n => format!("{} (x{})", stack.name, n),
+            };
+            label.truncate(32);
+            ctx.print_color(x, 11 + (j - offset) as i32, fg, black, label);
+        }
+    }
+    let action = if side == LootSide::Container { "take" } else { "put" };
+    let help = format!("ENTER {}  LEFT/RIGHT switch side  ESCAPE close", action);
+    ctx.print_color(7, 12 + SHOP_ROWS as i32, gray, black, help);
+
+    // Goes through the key's repeat delay so the same press doesn't also open the save menu
+    if gs.client.keys.check(Action::Cancel) {
+        return LootResult::Close;
+    }
+    match ctx.key {
+        Some(VirtualKeyCode::Up) => LootResult::NoSelection { side, selection: selection.saturating_sub(1) },
+        Some(VirtualKeyCode::Down) => {
+            LootResult::NoSelection { side, selection: usize::min(selection + 1, selected.len().saturating_sub(1)) }
+        }
+        Some(VirtualKeyCode::Left) => LootResult::NoSelection { side: LootSide::Container, selection: 0 },
+        Some(VirtualKeyCode::Right) => LootResult::NoSelection { side: LootSide::Backpack, selection: 0 },
+        Some(VirtualKeyCode::Return) if !selected.is_empty() => {
+            LootResult::Selected { side, selection, item: selected[selection].items[0] }
+        }
+        _ => LootResult::NoSelection { side, selection },
+    }
+}
+
+/// Things that can be done with a right-clicked tile
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ContextAction {
+    Examine,
+    PickUp,
+    ThrowAt,
+}
+
+impl Display for ContextAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextAction::Examine => write!(f, "Examine"),
+            ContextAction::PickUp => write!(f, "Pick up"),
+            ContextAction::ThrowAt => write!(f, "Throw at"),
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ContextMenuResult {
+    Close,
+    NoSelection { selection: usize },
+    Selected(ContextAction),
+}
+
+const CONTEXT_MENU_WIDTH: i32 = 12;
+
+/// What makes sense to do with a tile: items can only be picked up from underfoot, and only visible
+/// tiles within throwing range can be thrown at
+fn context_actions(ecs: &World, target: Point) -> Vec<ContextAction> {
+    let map = ecs.fetch::<Map>();
+    let player_entity = *ecs.fetch::<Entity>();
+    let player_pos = *ecs.fetch::<Point>();
+    let positions = ecs.read_storage::<Position>();
+    let items = ecs.read_storage::<Item>();
+    let backpack = ecs.read_storage::<InBackpack>();
+
+    let mut actions = vec![ContextAction::Examine];
+    if target == player_pos && (&positions, &items).join().any(|(pos, _)| pos.x == target.x && pos.y == target.y) {
+        actions.push(ContextAction::PickUp);
+    }
+    let range = ecs.read_storage::<CombatStats>().get(player_entity).map_or(1, throw_range);
+    let distance = rltk::DistanceAlg::Pythagoras.distance2d(player_pos, target);
+    let has_items = backpack.join().any(|pack| pack.owner == player_entity);
+    let visible = map.visible_tiles[map.xy_idx(target.x, target.y)];
+    if target != player_pos && visible && has_items && distance <= range as f32 {
+        actions.push(ContextAction::ThrowAt);
+    }
+    actions
+}
+
+/// Pops up a menu of actions next to a right-clicked tile
+pub fn show_context_menu(gs: &mut State, ctx: &mut Rltk, target: Point, selection: usize) -> ContextMenuResult {
+    let actions = context_actions(&gs.ecs, target);
+    let selection = selection.min(actions.len() - 1);
+
+    let white = RGB::named(rltk::WHITE);
+    let black = RGB::named(rltk::BLACK);
+    // Keep the menu on screen and above the log
+    let x = if target.x + CONTEXT_MENU_WIDTH + 2 < 80 { target.x + 1 } else { target.x - CONTEXT_MENU_WIDTH - 1 };
+    let y = i32::min(target.y, 41 - actions.len() as i32);
+    ctx.set_bg(target.x, target.y, RGB::named(rltk::CYAN));
+    ctx.draw_box(x, y, CONTEXT_MENU_WIDTH, actions.len() as i32 + 1, white, black);
+    for (i, action) in actions.iter().enumerate() {
+        let fg = if i == selection { RGB::named(rltk::MAGENTA) } else { white };
+        ctx.print_color(x + 1, y + 1 + i as i32, fg, black, action.to_string());
+    }
+
+    // Hovering highlights an action and clicking picks it, while clicking elsewhere closes the menu
+    let (mouse_x, mouse_y) = ctx.mouse_pos();
+    let mouse_row = mouse_y - y - 1;
+    let on_menu = mouse_x > x && mouse_x < x + CONTEXT_MENU_WIDTH && (0..actions.len() as i32).contains(&mouse_row);
+    if ctx.left_click {
+        if on_menu {
+            return ContextMenuResult::Selected(actions[mouse_row as usize]);
+        }
+        return ContextMenuResult::Close;
+    }
+
+    // Goes through the key's repeat delay so the same press doesn't also open the save menu
+    if gs.client.keys.check(Action::Cancel) {
+        return ContextMenuResult::Close;
+    }
+    match ctx.key {
+        Some(VirtualKeyCode::Up) => ContextMenuResult::NoSelection { selection: selection.saturating_sub(1) },
+        Some(VirtualKeyCode::Down) => {
+            ContextMenuResult::NoSelection { selection: usize::min(selection + 1, actions.len() - 1) }
+        }
+        Some(VirtualKeyCode::Return) => ContextMenuResult::Selected(actions[selection]),
+        _ if on_menu => ContextMenuResult::NoSelection { selection: mouse_row as usize },
+        _ => ContextMenuResult::NoSelection { selection },
+    }
+}
+
+/// Draws a log entry's segments in their colours, followed by how many times it repeated
+fn print_log_entry(ctx: &mut Rltk, x: i32, y: i32, entry: &LogEntry) {
+    let black = RGB::named(rltk::BLACK);
+    let mut x = x;
+    for segment in &entry.segments {
+        ctx.print_color(x, y, segment.color.unwrap_or_else(|| entry.category.color()), black, &segment.text);
+        x += segment.text.chars().count() as i32;
+    }
+    if entry.count > 1 {
+        ctx.print_color(x, y, RGB::named(rltk::GREY), black, format!(" x{}", entry.count));
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum LogViewerResult {
+    Close,
+    NoResponse { offset: usize, filter: Option<LogCategory> },
+}
+
+const LOG_VIEWER_ROWS: usize = 44;
+
+/// Moves the category filter `step` places along, going through "all" between the last and first
+fn cycle_log_filter(filter: Option<LogCategory>, step: isize) -> Option<LogCategory> {
+    let filters: Vec<Option<LogCategory>> =
+        std::iter::once(None).chain(LogCategory::ALL.iter().copied().map(Some)).collect();
+    let current = filters.iter().position(|f| *f == filter).unwrap_or(0) as isize;
+    filters[(current + step).rem_euclid(filters.len() as isize) as usize]
+}
+
+/// The whole message log on one screen, scrolled back `offset` messages from the newest and
+/// optionally showing just one category
+pub fn show_log_viewer(
+    gs: &mut State,
+    ctx: &mut Rltk,
+    offset: usize,
+    filter: Option<LogCategory>,
+) -> LogViewerResult {
+    let white = RGB::named(rltk::WHITE);
+    let grey = RGB::named(rltk::GREY);
+    let black = RGB::named(rltk::BLACK);
+
+    let log = gs.ecs.fetch::<GameLog>();
+    let entries: Vec<&LogEntry> =
+        log.entries.iter().filter(|entry| filter.is_none_or(|category| entry.category == category)).collect();
+    let max_offset = entries.len().saturating_sub(LOG_VIEWER_ROWS);
+    let offset = offset.min(max_offset);
+    let end = entries.len() - offset;
+    let start = end.saturating_sub(LOG_VIEWER_ROWS);
+
+    ctx.draw_box(0, 0, 79, 49, white, black);
+    ctx.print_color(3, 0, RGB::named(rltk::YELLOW), black, "Message Log");
+    let filter_name = filter.map_or("All".to_string(), |category| category.to_string());
+    ctx.print_color(2, 1, white, black, format!("Showing: {}", filter_name));
+    ctx.print_color(2, 2, grey, black, " Turn");
+    for (i, entry) in entries[start..end].iter().enumerate() {
+        let y = 3 + i as i32;
+        ctx.print_color(2, y, grey, black, format!("{:>5}", entry.turn));
+        print_log_entry(ctx, 9, y, entry);
+    }
+    if entries.is_empty() {
+        ctx.print_color(9, 3, grey, black, "(nothing yet)");
+    } else {
+        ctx.print_color(60, 1, grey, black, format!("{}-{} of {}", start + 1, end, entries.len()));
+    }
+    ctx.print_color(
+        3,
+        49,
+        white,
+        black,
+        "Up/Down/PgUp/PgDn/Home/End: scroll, Left/Right: filter, Escape: close",
+    );
+    drop(log);
+
+    if gs.client.keys.check(Action::Cancel) || gs.client.keys.check(Action::ShowLog) {
+        return LogViewerResult::Close;
+    }
+    let (offset, filter) = match ctx.key {
+        Some(VirtualKeyCode::Up) => (offset + 1, filter),
+        Some(VirtualKeyCode::Down) => (offset.saturating_sub(1), filter),
+        Some(VirtualKeyCode::PageUp) => (offset + LOG_VIEWER_ROWS, filter),
+        Some(VirtualKeyCode::PageDown) => (offset.saturating_sub(LOG_VIEWER_ROWS), filter),
+        Some(VirtualKeyCode::Home) => (max_offset, filter),
+        Some(VirtualKeyCode::End) => (0, filter),
+        // Changing the filter jumps back to the newest messages
+        Some(VirtualKeyCode::Left) => (0, cycle_log_filter(filter, -1)),
+        Some(VirtualKeyCode::Right) | Some(VirtualKeyCode::Tab) => (0, cycle_log_filter(filter, 1)),
+        _ => (offset, filter),
+    };
+    LogViewerResult::NoResponse { offset: offset.min(max_offset), filter }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum LookResult {
+    Close,
+    NoResponse { cursor: Point },
+}
+
+const LOOK_PANEL_WIDTH: i32 = 34;
+
+/// How hurt something looks, without giving away exact numbers
+fn health_band(stats: &CombatStats) -> &'static str {
+    match stats.hp * 100 / i32::max(1, stats.max_hp) {
+        100.. => "Unhurt",
+        75..=99 => "Lightly wounded",
+        50..=74 => "Wounded",
+        25..=49 => "Badly wounded",
+        _ => "Near death",
+    }
+}
+
+fn format_dice(dice: DiceType) -> String {
+    match dice.bonus {
+        0 => format!("{}d{}", dice.n_dice, dice.die_type),
+        bonus => format!("{}d{}{:+}", dice.n_dice, dice.die_type, bonus),
+    }
+}
+
+/// The attack something makes in melee, matching what the MeleeCombatSystem falls back to
+fn attack_description(ecs: &World, entity: Entity) -> String {
+    match ecs.read_storage::<NaturalAttack>().get(entity) {
+        Some(attack) => format!("{}, {} {}", attack.name, format_dice(attack.damage), attack.damage_type),
+        None => format!("bare hands, {} {}", format_dice(DiceType::default()), DamageType::Bludgeoning),
+    }
+}
+
+fn resistance_descriptions(ecs: &World, entity: Entity) -> Vec<String> {
+    let resistances = ecs.read_storage::<Resistances>();
+    let Some(resistances) = resistances.get(entity) else {
+        return Vec::new();
+    };
+    resistances
+        .modifiers
+        .iter()
+        .map(|(damage_type, percent)| match *percent {
+            100.. => format!("Immune to {}", damage_type),
+            1..=99 => format!("Resists {} ({}%)", damage_type, percent),
+            _ => format!("Vulnerable to {} ({}%)", damage_type, -percent),
+        })
+        .collect()
+}
+
+/// Lasting effects on something, like hunger or being held in place
+fn status_effects(ecs: &World, entity: Entity) -> Vec<String> {
+    let mut effects = Vec::new();
+    if let Some(clock) = ecs.read_storage::<HungerClock>().get(entity) {
+        match clock.state {
+            HungerState::WellFed => effects.push("Well fed".to_string()),
+            HungerState::Normal => {}
+            HungerState::Hungry => effects.push("Hungry".to_string()),
+            HungerState::Starving => effects.push("Starving".to_string()),
+        }
+    }
+    if let Some(immobilized) = ecs.read_storage::<Immobilized>().get(entity) {
+        effects.push(format!("Held in place for {} turns", immobilized.turns));
+    }
+    if let Some(telepathy) = ecs.read_storage::<Telepathy>().get(entity) {
+        effects.push(format!("Telepathic for {} turns", telepathy.turns));
+    }
+    if ecs.read_storage::<MovementSpeed>().get(entity).is_some_and(|speed| speed.encumbrance_delay_ms > 0) {
+        effects.push("Overburdened".to_string());
+    }
+    effects
+}
+
+/// Everything that can be told about something by looking at it
+fn look_details(ecs: &World, entity: Entity) -> Vec<String> {
+    let mut details = Vec::new();
+    if ecs.read_storage::<Player>().contains(entity) {
+        details.push("That's you!".to_string());
+    }
+    // Describing an unidentified potion or scroll would give away what it is
+    let real_name = ecs.read_storage::<Name>().get(entity).map(|name| name.name.clone()).unwrap_or_default();
+    if let Some(description) = ecs.read_storage::<Description>().get(entity) {
+        if ecs.fetch::<Identification>().is_identified(&real_name) {
+            details.extend(wrap(&description.text, LOOK_PANEL_WIDTH as usize - 3));
+        }
+    }
+    if let Some(stats) = ecs.read_storage::<CombatStats>().get(entity) {
+        details.push(health_band(stats).to_string());
+        details.push(format!("Attacks with {}", attack_description(ecs, entity)));
+    }
+    details.extend(resistance_descriptions(ecs, entity));
+    details.extend(status_effects(ecs, entity));
+    if ecs.read_storage::<Item>().contains(entity) {
+        details.extend(item_effects(ecs, entity, &real_name));
+    }
+    if ecs.read_storage::<Vendor>().contains(entity) {
+        details.push("Has goods to trade".to_string());
+    }
+    if ecs.read_storage::<Locked>().contains(entity) {
+        details.push("Locked".to_string());
+    }
+    if ecs.read_storage::<EntryTrigger>().contains(entity) {
+        details.push("Springs when stepped on".to_string());
+    }
+    details
+}
+
+/// Breaks text into lines of at most `width` characters, between words
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.len() + 1 + word.len() <= width => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    lines
+}
+
+/// Moves a cursor around the map with the movement keys, describing whatever visible things are
+/// under it in a panel on the other side of the screen
+pub fn show_look_mode(gs: &mut State, ctx: &mut Rltk, cursor: Point) -> LookResult {
+    let white = RGB::named(rltk::WHITE);
+    let black = RGB::named(rltk::BLACK);
+    ctx.print_color(5, 0, RGB::named(rltk::YELLOW), black, "Look around (movement keys, ESCAPE to stop):");
+    ctx.set_bg(cursor.x, cursor.y, RGB::named(rltk::CYAN));
+
+    let mut lines: Vec<(String, RGB)> = Vec::new();
+    {
+        let map = gs.ecs.fetch::<Map>();
+        let entities = gs.ecs.entities();
+        let names = gs.ecs.read_storage::<Name>();
+        let positions = gs.ecs.read_storage::<Position>();
+        let hidden = gs.ecs.read_storage::<Hidden>();
+        let identification = gs.ecs.fetch::<Identification>();
+
+        if !map.visible_tiles[map.xy_idx(cursor.x, cursor.y)] {
+            lines.push(("You can't see there.".to_string(), white));
+        } else {
+            for (entity, name, _, _) in (&entities, &names, &positions, !&hidden)
+                .join()
+                .filter(|(_, _, pos, _)| pos.x == cursor.x && pos.y == cursor.y)
+            {
+                lines.push((identification.name_of(&name.name), RGB::named(rltk::YELLOW)));
+                lines.extend(look_details(&gs.ecs, entity).into_iter().map(|detail| (format!(" {}", detail), white)));
+            }
+            if lines.is_empty() {
+                lines.push(("Nothing of note.".to_string(), white));
+            }
+        }
+    }
+
+    // Keep the panel out of the way of the cursor
+    let x = if cursor.x < 40 { 79 - LOOK_PANEL_WIDTH } else { 0 };
+    ctx.draw_box(x, 2, LOOK_PANEL_WIDTH, lines.len() as i32 + 1, white, black);
+    for (i, (line, color)) in lines.iter().enumerate() {
+        ctx.print_color(x + 1, 3 + i as i32, *color, black, line);
+    }
+
+    if gs.client.keys.check(Action::Cancel) || gs.client.keys.check(Action::Look) {
+        return LookResult::Close;
+    }
+    let action = ctx.key.and_then(|key| gs.client.keys.action_for(key));
+    let Some((_, delta_x, delta_y)) = DIRECTIONS.iter().find(|(direction, _, _)| Some(*direction) == action) else {
+        return LookResult::NoResponse { cursor };
+    };
+    let map = gs.ecs.fetch::<Map>();
+    let moved = Point::new(cursor.x + delta_x, cursor.y + delta_y);
+    if moved.x < 0 || moved.x >= map.width || moved.y < 0 || moved.y >= map.height {
+        return LookResult::NoResponse { cursor };
+    }
+    LookResult::NoResponse { cursor: moved }
+}
+
+/// Shows the player's stats, attack, resistances and condition, returning true once it's closed
+pub fn show_character_sheet(gs: &mut State, ctx: &mut Rltk) -> bool {
+    let white = RGB::named(rltk::WHITE);
+    let yellow = RGB::named(rltk::YELLOW);
+    let black = RGB::named(rltk::BLACK);
+    let player_entity = *gs.ecs.fetch::<Entity>();
+
+    let mut lines: Vec<(String, RGB)> = Vec::new();
+    {
+        let ecs = &gs.ecs;
+        let names = ecs.read_storage::<Name>();
+        let combat_stats = ecs.read_storage::<CombatStats>();
+        let stats = combat_stats.get(player_entity).unwrap();
+        if let Some(name) = names.get(player_entity) {
+            lines.push((name.name.clone(), yellow));
+        }
+        if let Some(exp) = ecs.read_storage::<Experience>().get(player_entity) {
+            lines.push((format!("Level {}, {} / {} XP", exp.level, exp.xp, exp.xp_to_next_level()), white));
+        }
+        lines.push((format!("Turns taken: {}", ecs.fetch::<GameClock>().turn), white));
+        lines.push((String::new(), white));
+
+        lines.push(("Combat".to_string(), yellow));
+        lines.push((format!(" HP: {} / {}", stats.hp, stats.max_hp), white));
+        lines.push((format!(" Power: {}", stats.power), white));
+        lines.push((format!(" Defense: {} (armour class {})", stats.defense, armour_class(stats.defense)), white));
+        lines.push((format!(" Attack: {}", attack_description(ecs, player_entity)), white));
+        for resistance in resistance_descriptions(ecs, player_entity) {
+            lines.push((format!(" {}", resistance), white));
+        }
+        lines.push((String::new(), white));
+
+        // There's nothing to wield or wear yet, so the player always fights with their natural attack
+        lines.push(("Equipment".to_string(), yellow));
+        lines.push((" None".to_string(), white));
+        lines.push((String::new(), white));
+
+        lines.push(("Carrying".to_string(), yellow));
+        let backpack = ecs.read_storage::<InBackpack>();
+        let items = ecs.read_storage::<Item>();
+        let weight = carried_weight(player_entity, &backpack, &items);
+        lines.push((format!(" {:.1} / {:.1} lbs", weight, carry_capacity(stats)), white));
+        if let Some(wallet) = ecs.read_storage::<Gold>().get(player_entity) {
+            lines.push((format!(" {} gold", wallet.amount), white));
+        }
+        lines.push((String::new(), white));
+
+        lines.push(("Condition".to_string(), yellow));
+        let effects = status_effects(ecs, player_entity);
+        if effects.is_empty() {
+            lines.push((" Nothing out of the ordinary".to_string(), white));
+        }
+        for effect in effects {
+            lines.push((format!(" {}", effect), white));
+        }
+    }
+
+    let (x, y, width) = (20, 4, 40);
+    ctx.draw_box(x, y, width, lines.len() as i32 + 3, white, black);
+    ctx.print_color(x + 3, y, yellow, black, "Character");
+    for (i, (line, color)) in lines.iter().enumerate() {
+        ctx.print_color(x + 2, y + 2 + i as i32, *color, black, line);
+    }
+    ctx.print_color(x + 3, y + lines.len() as i32 + 3, white, black, "ESCAPE to close");
+
+    gs.client.keys.check(Action::Cancel) || gs.client.keys.check(Action::CharacterSheet)
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum OptionsResult {
+    NoSelection { selection: usize },
+    /// A setting was changed and needs putting into effect
+    Changed { selection: usize },
+    Close,
+}
+
+/// Rows of the options screen, the last of which goes back to the main menu
+const OPTION_ROWS: usize = 5;
+
+fn option_labels(settings: &Settings) -> [String; OPTION_ROWS] {
+    [
+        format!("Scanlines: {}", if settings.scanlines { "On" } else { "Off" }),
+        format!("Font: {} (after restart)", settings.font),
+        format!("Key repeat delay: {} ms", settings.key_repeat_delay_ms),
+        format!("Message log length: {}", settings.log_length),
+        "Back".to_string(),
+    ]
+}
+
+/// Steps the setting on a row up or down, returning false if it was already at its limit
+fn change_option(settings: &mut Settings, row: usize, up: bool) -> bool {
+    let before = settings.clone();
+    match row {
+        0 => settings.scanlines = !settings.scanlines,
+        1 => {
+            settings.font = match settings.font {
+                Font::Terminal8x8 => Font::Vga8x16,
+                Font::Vga8x16 => Font::Terminal8x8,
+            }
+        }
+        2 => {
+            let (min, max) = KEY_REPEAT_DELAY_RANGE_MS;
+            let (delay, step) = (settings.key_repeat_delay_ms, KEY_REPEAT_DELAY_STEP_MS);
+            let delay = if up { delay + step } else { delay.saturating_sub(step) };
+            settings.key_repeat_delay_ms = delay.clamp(min, max);
+        }
+        3 => {
+            let (min, max) = LOG_LENGTH_RANGE;
+            let length = settings.log_length;
+            let length = if up { length + LOG_LENGTH_STEP } else { length.saturating_sub(LOG_LENGTH_STEP) };
+            settings.log_length = length.clamp(min, max);
+        }
+        _ => {}
+    }
+    *settings != before
+}
+
+/// Lists the settings, changing the highlighted one with Left and Right
+pub fn show_options(gs: &mut State, ctx: &mut Rltk, selection: usize) -> OptionsResult {
+    let black = RGB::named(rltk::BLACK);
+    ctx.print_color_centered(15, RGB::named(rltk::YELLOW), black, "Options");
+    for (i, label) in option_labels(&gs.client.settings).iter().enumerate() {
+        let fg = if i == selection { RGB::named(rltk::MAGENTA) } else { RGB::named(rltk::WHITE) };
+        ctx.print_color_centered(20 + i as i32, fg, black, label);
+    }
+    ctx.print_color_centered(27, RGB::named(rltk::GREY), black, "Left/Right to change, ESCAPE to go back");
+
+    let back = OPTION_ROWS - 1;
+    let changed = match ctx.key {
+        Some(VirtualKeyCode::Escape) => return OptionsResult::Close,
+        Some(VirtualKeyCode::Return) if selection == back => return OptionsResult::Close,
+        Some(VirtualKeyCode::Up) => return OptionsResult::NoSelection { selection: (selection + back) % OPTION_ROWS },
+        Some(VirtualKeyCode::Down) => return OptionsResult::NoSelection { selection: (selection + 1) % OPTION_ROWS },
+        Some(VirtualKeyCode::Left) => change_option(&mut gs.client.settings, selection, false),
+        Some(VirtualKeyCode::Right) | Some(VirtualKeyCode::Return) => {
+            change_option(&mut gs.client.settings, selection, true)
+        }
+        _ => false,
+    };
+    if changed {
+        OptionsResult::Changed { selection }
+    } else {
+        OptionsResult::NoSelection { selection }
+    }
+}
@@ -0,0 +1,82 @@
+use specs::prelude::*;
+
+use crate::components::{DamageType, HungerClock, HungerState};
+use crate::gamelog::LogCategory;
+use crate::{GameLog, RunState, SufferDamage};
+
+/// How many player turns each hunger state lasts before getting hungrier
+pub const WELL_FED_DURATION: i32 = 20;
+pub const NORMAL_DURATION: i32 = 200;
+pub const HUNGRY_DURATION: i32 = 200;
+
+pub struct HungerSystem {}
+
+impl<'a> System<'a> for HungerSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, HungerClock>,
+        ReadExpect<'a, Entity>,
+        ReadExpect<'a, RunState>,
+        WriteStorage<'a, SufferDamage>,
+        WriteExpect<'a, GameLog>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut hunger_clock, player_entity, runstate, mut inflict_damage, mut log): Self::SystemData,
+    ) {
+        // Hunger only advances when the player spends a turn
+        if *runstate != RunState::PlayerTurn {
+            return;
+        }
+
+        for (entity, clock) in (&entities, &mut hunger_clock).join() {
+            clock.duration -= 1;
+            if clock.duration > 0 {
+                continue;
+            }
+
+            let is_player = entity == *player_entity;
+            match clock.state {
+                HungerState::WellFed => {
+                    clock.state = HungerState::Normal;
+                    clock.duration = NORMAL_DURATION;
+                    if is_player {
+                        log.add(LogCategory::Status, "You are no longer well fed.");
+                    }
+                }
+                HungerState::Normal => {
+                    clock.state = HungerState::Hungry;
+                    clock.duration = HUNGRY_DURATION;
+                    if is_player {
+                        log.add(LogCategory::Status, "You are hungry.");
+                    }
+                }
+                HungerState::Hungry => {
+                    clock.state = HungerState::Starving;
+                    clock.duration = 0;
+                    if is_player {
+                        log.add(LogCategory::Status, "You are starving!");
+                    }
+                }
+                HungerState::Starving => {
+                    // Inflict damage from hunger every turn until the entity eats
+                    if is_player {
+                        log.add(LogCategory::Status, "Your hunger pangs are getting painful! You suffer 1 hp damage.");
+                    }
+                    SufferDamage::new_damage(&mut inflict_damage, entity, 1, DamageType::Starvation, None);
+                }
+            }
+        }
+    }
+}
+
+/// Attack power modifier from being well fed or going hungry
+pub fn power_modifier(clock: Option<&HungerClock>) -> i32 {
+    match clock.map(|c| c.state) {
+        Some(HungerState::WellFed) => 1,
+        Some(HungerState::Hungry) => -1,
+        Some(HungerState::Starving) => -2,
+        _ => 0,
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::{HashMap, HashSet};
+
+use rltk::RandomNumberGenerator;
+use serde::{Deserialize, Serialize};
+use specs::{Entity, World, WorldExt};
+
+use crate::spawner::{POTIONS, SCROLLS};
+use crate::Name;
+
+const POTION_ADJECTIVES: &[&str] = &["murky", "bubbling", "cloudy", "fizzy", "glowing", "smoky", "oily", "swirling"];
+const POTION_COLOURS: &[&str] = &["blue", "red", "green", "amber", "violet", "black", "silver", "pink"];
+const SCROLL_SYLLABLES: &[&str] = &["xy", "zzy", "ka", "lor", "em", "ips", "um", "fro", "bo", "zar", "nik", "tu"];
+
+/// Which magic items the player can recognise. Potions and scrolls hide their true names behind
+/// names made up at the start of each run until the player learns what they do.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Identification {
+    /// True item name to the name shown while unidentified
+    pub obfuscated: HashMap<String, String>,
+    pub identified: HashSet<String>,
+}
+
+impl Identification {
+    pub fn new(rng: &mut RandomNumberGenerator) -> Self {
+        let mut identification = Identification::default();
+        for potion in POTIONS.iter() {
+            let name = identification.unused_name(rng, |rng| {
+                format!("{} {} potion", pick(rng, POTION_ADJECTIVES), pick(rng, POTION_COLOURS))
+            });
+            identification.obfuscated.insert(potion.to_string(), name);
+        }
+        for scroll in SCROLLS.iter() {
+            let name = identification.unused_name(rng, |rng| {
+                let syllables = rng.roll_dice(1, 2) + 1;
+                let word: String = (0..syllables).map(|_| pick(rng, SCROLL_SYLLABLES)).collect();
+                format!("scroll of {}", word.to_uppercase())
+            });
+            identification.obfuscated.insert(scroll.to_string(), name);
+        }
+        identification
+    }
+
+    /// Keeps generating names until one isn't already taken by another item
+    fn unused_name<F>(&self, rng: &mut RandomNumberGenerator, mut generate: F) -> String
+    where
+        F: FnMut(&mut RandomNumberGenerator) -> String,
+    {
+        loop {
+            let name = generate(rng);
+            if !self.obfuscated.values().any(|taken| *taken == name) {
+                return name;
+            }
+        }
+    }
+
+    pub fn is_identified(&self, name: &str) -> bool {
+        !self.obfuscated.contains_key(name) || self.identified.contains(name)
+    }
+
+    /// Learns what an item is, returning true if it wasn't known before
+    pub fn identify(&mut self, name: &str) -> bool {
+        !self.is_identified(name) && self.identified.insert(name.to_string())
+    }
+
+    /// The name the player knows an item by
+    pub fn name_of(&self, name: &str) -> String {
+        match self.obfuscated.get(name) {
+            Some(obfuscated) if !self.identified.contains(name) => obfuscated.clone(),
+            _ => name.to_string(),
+        }
+    }
+}
+
+/// The name the player knows an entity by
+pub fn display_name(ecs: &World, entity: Entity) -> String {
+    let names = ecs.read_storage::<Name>();
+    ecs.fetch::<Identification>().name_of(&names.get(entity).unwrap().name)
+}
+
+fn pick<'a>(rng: &mut RandomNumberGenerator, options: &[&'a str]) -> &'a str {
+    options[rng.roll_dice(1, options.len() as i32) as usize - 1]
+}
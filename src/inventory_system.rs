@@ -1,7 +1,67 @@
-use specs::{Entities, Entity, Join, ReadExpect, ReadStorage, System, WriteExpect, WriteStorage};
+use rltk::{LineAlg, Point, RandomNumberGenerator};
+use specs::{Entities, Entity, Join, ReadExpect, ReadStorage, System, World, WorldExt, WriteExpect, WriteStorage};
 
-use crate::{CombatStats, GameLog, Name, Position, ProvidesHealing, WantsToDropItem, WantsToUseItem};
-use crate::components::{Consumable, InBackpack, WantsToPickupItem};
+use crate::gamelog::LogCategory;
+use crate::{
+    CombatStats, GameLog, Map, Name, Position, ProvidesHealing, RunState, SufferDamage, WantsToDropItem, WantsToUseItem,
+};
+use crate::components::{
+    BlocksTile, Consumable, Gold, GrantsTelepathy, HungerClock, HungerState, InBackpack, InContainer, InflictsDamage,
+    Item, MagicMapper, ProvidesFood, Resistances, Telepathy, ThrowingWeapon, WantsToPickupItem, WantsToThrowItem,
+};
+use crate::damage_system::resisted_damage;
+use crate::encumbrance_system::within_max_load;
+use crate::map::TileType;
+use crate::melee_combat_system::{armour_class, resistance_note, resolve_attack, AttackOutcome};
+use crate::hunger_system::{power_modifier, WELL_FED_DURATION};
+use crate::identification::Identification;
+
+/// Identical consumables in a backpack or container, shown as a single entry
+pub struct ItemStack {
+    /// What the player knows the items as
+    pub name: String,
+    pub real_name: String,
+    pub items: Vec<Entity>,
+}
+
+/// Groups an entity's backpack into stacks. Consumables with the same name share a stack, while
+/// everything else gets an entry of its own.
+pub fn stacked_inventory(ecs: &World, owner: Entity) -> Vec<ItemStack> {
+    let entities = ecs.entities();
+    let backpack = ecs.read_storage::<InBackpack>();
+    let items = (&entities, &backpack).join().filter(|(_, pack)| pack.owner == owner).map(|(e, _)| e);
+    stack_items(ecs, items)
+}
+
+/// Groups what's inside a container into stacks, the same way as a backpack
+pub fn stacked_contents(ecs: &World, container: Entity) -> Vec<ItemStack> {
+    let entities = ecs.entities();
+    let contents = ecs.read_storage::<InContainer>();
+    let items = (&entities, &contents).join().filter(|(_, inside)| inside.container == container).map(|(e, _)| e);
+    stack_items(ecs, items)
+}
+
+fn stack_items(ecs: &World, items: impl Iterator<Item = Entity>) -> Vec<ItemStack> {
+    let names = ecs.read_storage::<Name>();
+    let consumables = ecs.read_storage::<Consumable>();
+    let identification = ecs.fetch::<Identification>();
+
+    let mut stacks: Vec<ItemStack> = Vec::new();
+    for (entity, name) in items.filter_map(|e| names.get(e).map(|name| (e, name))) {
+        let existing = stacks.iter_mut().find(|stack| {
+            consumables.contains(entity) && consumables.contains(stack.items[0]) && stack.real_name == name.name
+        });
+        match existing {
+            Some(stack) => stack.items.push(entity),
+            None => stacks.push(ItemStack {
+                name: identification.name_of(&name.name),
+                real_name: name.name.clone(),
+                items: vec![entity],
+            }),
+        }
+    }
+    stacks
+}
 
 pub struct ItemCollectionSystem {}
 
@@ -13,14 +73,58 @@ impl<'a> System<'a> for ItemCollectionSystem {
         WriteStorage<'a, Position>,
         ReadStorage<'a, Name>,
         WriteStorage<'a, InBackpack>,
+        ReadExpect<'a, Map>,
+        ReadStorage<'a, Item>,
+        ReadStorage<'a, CombatStats>,
+        ReadExpect<'a, Identification>,
+        Entities<'a>,
+        WriteStorage<'a, Gold>,
     );
 
     fn run(
         &mut self,
-        (player_entity, mut log, mut wants_pickup, mut positions, names, mut backpack): Self::SystemData,
+        (
+            player_entity,
+            mut log,
+            mut wants_pickup,
+            mut positions,
+            names,
+            mut backpack,
+            map,
+            items,
+            combat_stats,
+            identification,
+            entities,
+            mut gold,
+        ): Self::SystemData,
     ) {
         for pickup in wants_pickup.join() {
-            positions.remove(pickup.item);
+            let item_name = identification.name_of(&names.get(pickup.item).unwrap().name);
+            // Refuse anything that would take the collector over their maximum load
+            if let (Some(stats), Some(item)) = (combat_stats.get(pickup.collected_by), items.get(pickup.item)) {
+                if !within_max_load(pickup.collected_by, stats, item.weight, &backpack, &items) {
+                    if pickup.collected_by == *player_entity {
+                        log.add(LogCategory::Item, format!("The {} is too heavy to carry.", item_name));
+                    }
+                    continue;
+                }
+            }
+
+            // Someone else may have grabbed it first
+            if positions.remove(pickup.item).is_none() {
+                continue;
+            }
+
+            // Gold goes straight into the collector's wallet rather than their backpack
+            let pile = gold.get(pickup.item).map(|pile| pile.amount);
+            if let (Some(amount), Some(wallet)) = (pile, gold.get_mut(pickup.collected_by)) {
+                wallet.amount += amount;
+                entities.delete(pickup.item).expect("Delete failed");
+                if pickup.collected_by == *player_entity {
+                    log.add(LogCategory::Item, format!("You pick up {} gold.", amount));
+                }
+                continue;
+            }
             backpack
                 .insert(
                     pickup.item,
@@ -31,7 +135,12 @@ impl<'a> System<'a> for ItemCollectionSystem {
                 .expect("Unable to insert backpack entry");
 
             if pickup.collected_by == *player_entity {
-                log.entries.push_back(format!("You picked up {}", names.get(pickup.item).unwrap().name));
+                log.add(LogCategory::Item, format!("You picked up {}", item_name));
+            } else if let Some(pos) = positions.get(pickup.collected_by) {
+                if map.is_visible(pos.x, pos.y) {
+                    let name = names.get(pickup.collected_by).unwrap();
+                    log.add(LogCategory::Item, format!("{} picks up {}.", name.name, item_name));
+                }
             }
         }
 
@@ -51,28 +160,123 @@ impl<'a> System<'a> for ItemUseSystem {
         ReadStorage<'a, ProvidesHealing>,
         ReadStorage<'a, Consumable>,
         WriteStorage<'a, CombatStats>,
+        ReadStorage<'a, ProvidesFood>,
+        WriteStorage<'a, HungerClock>,
+        ReadStorage<'a, InflictsDamage>,
+        WriteStorage<'a, SufferDamage>,
+        ReadStorage<'a, Position>,
+        ReadExpect<'a, Map>,
+        ReadStorage<'a, Resistances>,
+        WriteExpect<'a, Identification>,
+        WriteExpect<'a, RunState>,
+        ReadStorage<'a, MagicMapper>,
+        ReadStorage<'a, GrantsTelepathy>,
+        WriteStorage<'a, Telepathy>,
     );
 
     fn run(
         &mut self,
-        (player_entity, mut log, entities, mut wants_use, names, provides_healing, consumables, mut combat_stats): Self::SystemData,
+        (
+            player_entity,
+            mut log,
+            entities,
+            mut wants_use,
+            names,
+            provides_healing,
+            consumables,
+            mut combat_stats,
+            provides_food,
+            mut hunger_clocks,
+            inflicts_damage,
+            mut suffer_damage,
+            positions,
+            map,
+            resistances,
+            mut identification,
+            mut runstate,
+            magic_mappers,
+            grants_telepathy,
+            mut telepathy,
+        ): Self::SystemData,
     ) {
-        for (entity, use_item, stats) in (&entities, &mut wants_use, &mut combat_stats).join() {
+        for (entity, use_item) in (&entities, &wants_use).join() {
+            let is_player = entity == *player_entity;
+            // Only mention what monsters do with items when the player can see them
+            let seen = is_player || positions.get(entity).is_some_and(|pos| map.is_visible(pos.x, pos.y));
+            let user_name = &names.get(entity).unwrap().name;
+            let real_name = &names.get(use_item.item).unwrap().name;
+            let item_name = &identification.name_of(real_name);
+
             let healing_item = provides_healing.get(use_item.item);
             match healing_item {
                 None => {}
                 Some(potion) => {
-                    stats.hp = i32::min(stats.max_hp, stats.hp + potion.heal_amount);
-                    if entity == *player_entity {
-                        log.entries.push_back(format!(
-                            "You drink the {}, healing {} hp",
-                            names.get(use_item.item).unwrap().name,
-                            potion.heal_amount
+                    if let Some(stats) = combat_stats.get_mut(entity) {
+                        stats.hp = i32::min(stats.max_hp, stats.hp + potion.heal_amount);
+                    }
+                    if is_player {
+                        let message = format!("You drink the {}, healing {} hp", item_name, potion.heal_amount);
+                        log.add(LogCategory::Item, message);
+                    } else if seen {
+                        log.add(LogCategory::Item, format!("{} drinks a {}.", user_name, item_name));
+                    }
+                }
+            }
+
+            // Offensive items damage whatever is standing on the targeted tile
+            if let (Some(damage), Some(target)) = (inflicts_damage.get(use_item.item), use_item.target) {
+                if seen {
+                    log.add(LogCategory::Item, format!("{} reads the {}!", user_name, item_name));
+                }
+                let idx = map.xy_idx(target.x, target.y);
+                for victim in map.tile_content[idx].iter().filter(|e| combat_stats.contains(**e)) {
+                    let (amount, damage_type) = (damage.damage, damage.damage_type);
+                    SufferDamage::new_damage(&mut suffer_damage, *victim, amount, damage_type, Some(entity));
+                    if seen || *victim == *player_entity {
+                        let landed = resisted_damage(resistances.get(*victim), damage.damage, damage.damage_type);
+                        log.add(LogCategory::Combat, format!(
+                            "{} hits {} for {} {} damage{}.",
+                            item_name,
+                            names.get(*victim).unwrap().name,
+                            landed,
+                            damage.damage_type,
+                            resistance_note(damage.damage, landed)
                         ));
                     }
                 }
             }
-    
+
+            // Food resets the eater's hunger clock
+            if provides_food.contains(use_item.item) {
+                if let Some(clock) = hunger_clocks.get_mut(entity) {
+                    clock.state = HungerState::WellFed;
+                    clock.duration = WELL_FED_DURATION;
+                }
+                if is_player {
+                    log.add(LogCategory::Item, format!("You eat the {}.", item_name));
+                } else if seen {
+                    log.add(LogCategory::Item, format!("{} eats a {}.", user_name, item_name));
+                }
+            }
+
+            // Magic mapping sweeps across the map over the next few ticks
+            if magic_mappers.contains(use_item.item) && is_player {
+                log.add(LogCategory::Item, "The map is revealed to you!");
+                *runstate = RunState::MagicMapReveal { row: 0 };
+            }
+
+            if let Some(grant) = grants_telepathy.get(use_item.item) {
+                telepathy.insert(entity, Telepathy { turns: grant.turns }).expect("Unable to grant telepathy");
+                if is_player {
+                    log.add(LogCategory::Item, "You sense the minds around you.");
+                }
+            }
+
+            // Seeing an item used reveals what it is
+            if seen && identification.identify(real_name) {
+                log.add(LogCategory::Item, format!("You identify the {} as a {}.", item_name, real_name));
+            }
+
             // Delete consumables
             let consumable = consumables.get(use_item.item);
             match consumable {
@@ -97,11 +301,21 @@ impl<'a> System<'a> for ItemDropSystem {
         ReadStorage<'a, Name>,
         WriteStorage<'a, Position>,
         WriteStorage<'a, InBackpack>,
+        ReadExpect<'a, Identification>,
     );
 
     fn run(
         &mut self,
-        (player_entity, mut log, entities, mut wants_drop, names, mut positions, mut backpack): Self::SystemData,
+        (
+            player_entity,
+            mut log,
+            entities,
+            mut wants_drop,
+            names,
+            mut positions,
+            mut backpack,
+            identification,
+        ): Self::SystemData,
     ) {
         for (entity, to_drop) in (&entities, &mut wants_drop).join() {
             let mut drop_pos = Position { x: 0, y: 0 };
@@ -114,10 +328,10 @@ impl<'a> System<'a> for ItemDropSystem {
             backpack.remove(to_drop.item);
 
             if entity == *player_entity {
-                log.entries.push_back(format!(
+                log.add(LogCategory::Item, format!(
                     "{} dropped {}.",
                     names.get(*player_entity).unwrap().name,
-                    names.get(to_drop.item).unwrap().name
+                    identification.name_of(&names.get(to_drop.item).unwrap().name)
                 ));
             }
         }
@@ -125,3 +339,170 @@ impl<'a> System<'a> for ItemDropSystem {
         wants_drop.clear();
     }
 }
+
+/// Anything can be thrown this far, plus half the thrower's power
+const BASE_THROW_RANGE: i32 = 3;
+/// Thrown potions splash everything within this many tiles of where they shatter
+const SPLASH_RADIUS: i32 = 1;
+
+/// How many tiles something can throw an item
+pub fn throw_range(stats: &CombatStats) -> i32 {
+    BASE_THROW_RANGE + stats.power / 2
+}
+
+pub struct ItemThrowSystem {}
+
+impl<'a> System<'a> for ItemThrowSystem {
+    type SystemData = (
+        ReadExpect<'a, Entity>,
+        WriteExpect<'a, GameLog>,
+        WriteExpect<'a, RandomNumberGenerator>,
+        WriteExpect<'a, Identification>,
+        ReadExpect<'a, Map>,
+        Entities<'a>,
+        WriteStorage<'a, WantsToThrowItem>,
+        ReadStorage<'a, Name>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, InBackpack>,
+        WriteStorage<'a, CombatStats>,
+        ReadStorage<'a, BlocksTile>,
+        ReadStorage<'a, ProvidesHealing>,
+        ReadStorage<'a, ThrowingWeapon>,
+        WriteStorage<'a, SufferDamage>,
+        ReadStorage<'a, HungerClock>,
+        ReadStorage<'a, Resistances>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            player_entity,
+            mut log,
+            mut rng,
+            mut identification,
+            map,
+            entities,
+            mut wants_throw,
+            names,
+            mut positions,
+            mut backpack,
+            mut combat_stats,
+            blockers,
+            provides_healing,
+            throwing_weapons,
+            mut suffer_damage,
+            hunger_clocks,
+            resistances,
+        ): Self::SystemData,
+    ) {
+        for (entity, throw) in (&entities, &wants_throw).join() {
+            let Some(from) = positions.get(entity).map(|pos| Point::new(pos.x, pos.y)) else {
+                continue;
+            };
+            let seen = entity == *player_entity || map.is_visible(from.x, from.y);
+            let thrower_name = &names.get(entity).unwrap().name;
+            let real_name = &names.get(throw.item).unwrap().name;
+            let item_name = identification.name_of(real_name);
+
+            // Only things being carried can be thrown, and only as far as the thrower can manage
+            if backpack.get(throw.item).is_none_or(|pack| pack.owner != entity) {
+                if seen {
+                    log.add(LogCategory::Item, format!("{} isn't carrying the {}.", thrower_name, item_name));
+                }
+                continue;
+            }
+            let range = combat_stats.get(entity).map_or(1, throw_range);
+            if rltk::DistanceAlg::Pythagoras.distance2d(from, throw.target) > range as f32 {
+                if seen {
+                    log.add(LogCategory::Item, format!("{} can't throw the {} that far.", thrower_name, item_name));
+                }
+                continue;
+            }
+
+            // The item flies until it hits a wall or something standing in the way
+            let mut landing = from;
+            let mut struck: Option<Entity> = None;
+            for point in rltk::line2d(LineAlg::Bresenham, from, throw.target).into_iter().skip(1) {
+                let idx = map.xy_idx(point.x, point.y);
+                if map.tiles[idx] == TileType::Wall {
+                    break;
+                }
+                landing = point;
+                struck = map.tile_content[idx].iter().copied().find(|e| blockers.contains(*e));
+                if struck.is_some() {
+                    break;
+                }
+            }
+
+            backpack.remove(throw.item);
+            if seen {
+                log.add(LogCategory::Item, format!("{} throws the {}.", thrower_name, item_name));
+            }
+
+            // Potions shatter where they land and splash everything nearby
+            if let Some(potion) = provides_healing.get(throw.item) {
+                if map.is_visible(landing.x, landing.y) {
+                    log.add(LogCategory::Item, format!("The {} shatters!", item_name));
+                    if identification.identify(real_name) {
+                        log.add(LogCategory::Item, format!("You identify the {} as a {}.", item_name, real_name));
+                    }
+                }
+                for (target, pos, stats) in (&entities, &positions, &mut combat_stats).join() {
+                    let in_range = i32::abs(pos.x - landing.x) <= SPLASH_RADIUS
+                        && i32::abs(pos.y - landing.y) <= SPLASH_RADIUS;
+                    if in_range && stats.hp > 0 {
+                        stats.hp = i32::min(stats.max_hp, stats.hp + potion.heal_amount);
+                        if map.is_visible(pos.x, pos.y) {
+                            let target_name = &names.get(target).unwrap().name;
+                            let message = format!("{} is healed for {} hp.", target_name, potion.heal_amount);
+                            log.add(LogCategory::Item, message);
+                        }
+                    }
+                }
+                entities.delete(throw.item).expect("Delete failed");
+                continue;
+            }
+
+            positions.insert(throw.item, Position { x: landing.x, y: landing.y }).expect("Could not throw item");
+
+            let Some(victim) = struck.filter(|victim| combat_stats.contains(*victim)) else {
+                continue;
+            };
+            let victim_name = &names.get(victim).unwrap().name;
+            let Some(weapon) = throwing_weapons.get(throw.item) else {
+                if seen {
+                    log.add(LogCategory::Combat, format!("The {} bounces off {}.", item_name, victim_name));
+                }
+                continue;
+            };
+
+            // Weapons have to hit like any other attack
+            let attack_bonus = combat_stats.get(entity).map_or(0, |stats| stats.power)
+                + power_modifier(hunger_clocks.get(entity));
+            let defense = combat_stats.get(victim).unwrap().defense;
+            let landed = |damage| resisted_damage(resistances.get(victim), damage, weapon.damage_type);
+            match resolve_attack(&mut rng, attack_bonus, weapon.damage, armour_class(defense)) {
+                AttackOutcome::Fumble | AttackOutcome::Miss => {
+                    if seen {
+                        log.add(LogCategory::Combat, format!("The {} misses {}.", item_name, victim_name));
+                    }
+                }
+                AttackOutcome::Hit(damage) | AttackOutcome::Critical(damage) => {
+                    if seen || victim == *player_entity {
+                        log.add(LogCategory::Combat, format!(
+                            "The {} hits {} for {} {} damage{}.",
+                            item_name,
+                            victim_name,
+                            landed(damage),
+                            weapon.damage_type,
+                            resistance_note(damage, landed(damage))
+                        ));
+                    }
+                    SufferDamage::new_damage(&mut suffer_damage, victim, damage, weapon.damage_type, Some(entity));
+                }
+            }
+        }
+
+        wants_throw.clear();
+    }
+}
@@ -0,0 +1,264 @@
+//! Maps keys to player actions, so gameplay code asks "was Search pressed?" rather than "was S pressed?".
+//!
+//! Bindings can be overridden from `keybindings.json`, an object keyed by action name. Actions left out of
+//! the file keep their default binding, less any keys the file gives to other actions, so WASD movement
+//! takes S away from Search:
+//!
+//! ```json
+//! {
+//!     "MoveNorth": { "keys": ["Up", "W"], "min_delay_ms": 0, "repeat_delay_ms": 0 },
+//!     "MoveSouth": { "keys": ["Down", "S"], "min_delay_ms": 0, "repeat_delay_ms": 0 },
+//!     "Search": { "keys": ["F"], "min_delay_ms": 300, "repeat_delay_ms": 500 }
+//! }
+//! ```
+//!
+//! Key names are those of [`VirtualKeyCode`], e.g. `A`, `Key1`, `Numpad8`, `Escape`, `Return`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+
+use rltk::VirtualKeyCode;
+use serde::{Deserialize, Serialize};
+
+use crate::keys_util::{self, KeyPress};
+
+pub const KEY_BINDINGS_PATH: &str = "./keybindings.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    MoveNorth,
+    MoveSouth,
+    MoveWest,
+    MoveEast,
+    MoveNorthWest,
+    MoveNorthEast,
+    MoveSouthWest,
+    MoveSouthEast,
+    /// Lets a turn pass without doing anything
+    Wait,
+    PickUp,
+    ToggleInventory,
+    DropMode,
+    ThrowMode,
+    Search,
+    /// Walks towards the nearest unexplored part of the map until something interesting turns up
+    AutoExplore,
+    /// Opens the full message log
+    ShowLog,
+    /// Moves a cursor around the map to inspect what's there
+    Look,
+    CharacterSheet,
+    /// Closes whatever is open, or saves and quits to the main menu
+    Cancel,
+}
+
+/// How an action is written in the config file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BindingConfig {
+    keys: Vec<String>,
+    min_delay_ms: u64,
+    repeat_delay_ms: u64,
+}
+
+#[derive(Debug)]
+pub enum BindingError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    UnknownKey { action: Action, key: String },
+    Conflict { key: VirtualKeyCode, first: Action, second: Action },
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingError::Io(e) => write!(f, "could not read {}: {}", KEY_BINDINGS_PATH, e),
+            BindingError::Parse(e) => write!(f, "could not parse {}: {}", KEY_BINDINGS_PATH, e),
+            BindingError::UnknownKey { action, key } => write!(f, "unknown key {:?} bound to {:?}", key, action),
+            BindingError::Conflict { key, first, second } => {
+                write!(f, "{:?} is bound to both {:?} and {:?}", key, first, second)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Binding {
+    keys: Vec<VirtualKeyCode>,
+    press: KeyPress,
+}
+
+#[derive(Debug)]
+pub struct KeyBindings {
+    bindings: HashMap<Action, Binding>,
+}
+
+impl KeyBindings {
+    /// Loads bindings from the config file on top of the defaults, which repeat after `repeat_delay_ms`.
+    /// A missing file just means defaults.
+    pub fn load(path: &str, repeat_delay_ms: u64) -> Result<KeyBindings, BindingError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => KeyBindings::parse(&contents, repeat_delay_ms),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(KeyBindings::with_repeat_delay(repeat_delay_ms)),
+            Err(e) => Err(BindingError::Io(e)),
+        }
+    }
+
+    /// Applies bindings written as in the config file on top of the defaults. Keys the config binds are
+    /// taken from the defaults of actions it leaves out, but binding a key twice within it is an error.
+    fn parse(contents: &str, repeat_delay_ms: u64) -> Result<KeyBindings, BindingError> {
+        let mut bindings = KeyBindings::with_repeat_delay(repeat_delay_ms);
+        let config: HashMap<Action, BindingConfig> = serde_json::from_str(contents).map_err(BindingError::Parse)?;
+
+        let mut configured = Vec::new();
+        for (action, binding) in config {
+            let keys = binding
+                .keys
+                .iter()
+                .map(|name| parse_key(name).ok_or_else(|| BindingError::UnknownKey { action, key: name.clone() }))
+                .collect::<Result<Vec<_>, _>>()?;
+            let press = KeyPress::new(binding.min_delay_ms, binding.repeat_delay_ms);
+            configured.push((action, Binding { keys, press }));
+        }
+        for binding in bindings.bindings.values_mut() {
+            binding.keys.retain(|key| !configured.iter().any(|(_, configured)| configured.keys.contains(key)));
+        }
+        bindings.bindings.extend(configured);
+        bindings.validate()?;
+        Ok(bindings)
+    }
+
+    /// The default bindings, with keys that repeat doing so after `repeat_delay_ms` rather than the usual delay
+    pub fn with_repeat_delay(repeat_delay_ms: u64) -> KeyBindings {
+        let mut bindings = KeyBindings::default();
+        for binding in bindings.bindings.values_mut().filter(|binding| binding.press.repeat_delay_ms > 0) {
+            binding.press.repeat_delay_ms = repeat_delay_ms;
+        }
+        bindings
+    }
+
+    /// Every key may only trigger a single action
+    fn validate(&self) -> Result<(), BindingError> {
+        let mut bound: HashMap<VirtualKeyCode, Action> = HashMap::new();
+        // Sorted so the reported conflict doesn't depend on hash order
+        let mut actions: Vec<&Action> = self.bindings.keys().collect();
+        actions.sort_by_key(|action| format!("{:?}", action));
+        for action in actions {
+            for key in &self.bindings[action].keys {
+                if let Some(first) = bound.insert(*key, *action) {
+                    if first != *action {
+                        return Err(BindingError::Conflict { key: *key, first, second: *action });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks if any key bound to the action can be pressed, respecting the action's delays
+    pub fn check(&mut self, action: Action) -> bool {
+        match self.bindings.get_mut(&action) {
+            Some(binding) => keys_util::check_press(&binding.keys, Some(&mut binding.press)),
+            None => false,
+        }
+    }
+
+    /// The action a key is bound to, for screens that react to single key presses rather than held keys
+    pub fn action_for(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.bindings.iter().find(|(_, binding)| binding.keys.contains(&key)).map(|(action, _)| *action)
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        use VirtualKeyCode::*;
+
+        // Movement and waiting are held down and limited by movement speed instead
+        let bindings = [
+            (Action::MoveNorth, vec![Up, Numpad8, K], KeyPress::new(0, 0)),
+            (Action::MoveSouth, vec![Down, Numpad2, J], KeyPress::new(0, 0)),
+            (Action::MoveWest, vec![Left, Numpad4, H], KeyPress::new(0, 0)),
+            (Action::MoveEast, vec![Right, Numpad6, L], KeyPress::new(0, 0)),
+            (Action::MoveNorthWest, vec![Numpad7, Y], KeyPress::new(0, 0)),
+            (Action::MoveNorthEast, vec![Numpad9, U], KeyPress::new(0, 0)),
+            (Action::MoveSouthWest, vec![Numpad1, B], KeyPress::new(0, 0)),
+            (Action::MoveSouthEast, vec![Numpad3, N], KeyPress::new(0, 0)),
+            (Action::Wait, vec![Numpad5, Period], KeyPress::new(0, 0)),
+            (Action::PickUp, vec![E], KeyPress::new(100, 500)),
+            (Action::ToggleInventory, vec![I], KeyPress::new(100, 500)),
+            (Action::DropMode, vec![G], KeyPress::new(100, 500)),
+            (Action::ThrowMode, vec![T], KeyPress::new(100, 500)),
+            (Action::Search, vec![S], KeyPress::new(300, 500)),
+            (Action::AutoExplore, vec![X], KeyPress::new(100, 500)),
+            (Action::ShowLog, vec![M], KeyPress::new(100, 500)),
+            (Action::Look, vec![Semicolon], KeyPress::new(100, 500)),
+            (Action::CharacterSheet, vec![C], KeyPress::new(100, 500)),
+            (Action::Cancel, vec![Escape], KeyPress::new(100, 500)),
+        ];
+        KeyBindings {
+            bindings: bindings.into_iter().map(|(action, keys, press)| (action, Binding { keys, press })).collect(),
+        }
+    }
+}
+
+/// Keys that can be named in the config file
+const BINDABLE_KEYS: &[VirtualKeyCode] = {
+    use VirtualKeyCode::*;
+    &[
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z, Key0, Key1, Key2, Key3, Key4,
+        Key5, Key6, Key7, Key8, Key9, Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7,
+        Numpad8, Numpad9, NumpadAdd, NumpadSubtract, NumpadEnter, Up, Down, Left, Right, Home, End, PageUp, PageDown,
+        Insert, Delete, Escape, Return, Space, Tab, Back, Comma, Period, Slash, Semicolon, Apostrophe, Minus, Equals,
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    ]
+};
+
+fn parse_key(name: &str) -> Option<VirtualKeyCode> {
+    BINDABLE_KEYS.iter().copied().find(|key| format!("{:?}", key) == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_have_no_conflicts() {
+        assert!(KeyBindings::default().validate().is_ok());
+    }
+
+    #[test]
+    fn configured_keys_replace_conflicting_defaults() {
+        let config = r#"{ "MoveSouth": { "keys": ["Down", "S"], "min_delay_ms": 0, "repeat_delay_ms": 0 } }"#;
+        let bindings = KeyBindings::parse(config, 500).unwrap();
+        assert_eq!(bindings.action_for(VirtualKeyCode::S), Some(Action::MoveSouth));
+        assert!(bindings.bindings[&Action::Search].keys.is_empty());
+        // The configured keys replace the action's own defaults, and other actions keep theirs
+        assert_eq!(bindings.action_for(VirtualKeyCode::J), None);
+        assert_eq!(bindings.action_for(VirtualKeyCode::K), Some(Action::MoveNorth));
+    }
+
+    #[test]
+    fn key_bound_twice_in_config_conflicts() {
+        let config = r#"{
+            "Search": { "keys": ["F"], "min_delay_ms": 300, "repeat_delay_ms": 500 },
+            "Wait": { "keys": ["F"], "min_delay_ms": 0, "repeat_delay_ms": 0 }
+        }"#;
+        match KeyBindings::parse(config, 500) {
+            Err(BindingError::Conflict { key, first, second }) => {
+                assert_eq!(key, VirtualKeyCode::F);
+                assert_eq!((first, second), (Action::Search, Action::Wait));
+            }
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_key_is_rejected() {
+        let config = r#"{ "Search": { "keys": ["Banana"], "min_delay_ms": 0, "repeat_delay_ms": 0 } }"#;
+        assert!(matches!(
+            KeyBindings::parse(config, 500),
+            Err(BindingError::UnknownKey { action: Action::Search, .. })
+        ));
+    }
+}
@@ -4,10 +4,10 @@ use rltk::VirtualKeyCode;
 
 #[derive(Clone, Copy, PartialEq, Default, Debug)]
 pub enum KeyState {
-    PRESSED,
-    HOLD,
+    Pressed,
+    Hold,
     #[default]
-    RELEASED,
+    Released,
 }
 
 /// Key press with a repeat delay
@@ -25,41 +25,53 @@ impl KeyPress {
         KeyPress {
             min_delay_ms,
             last_press_time: None,
-            state: KeyState::RELEASED,
+            state: KeyState::Released,
             repeat_delay_ms,
         }
     }
 }
 
-/// Checks if key is and can be pressed, checking its KeyPress configuration and updating it
+/// Checks if any of the keys is and can be pressed, checking their shared KeyPress configuration and updating it
 ///
 /// Note: Rltk INPUT pressed key set only contains those pressed all at once until released.
 /// Meaning if you press multiple keys and hold, then press another key afterwards, it won't be
 /// included in the key set and won't register until the initial key set is released.
-pub fn check_press(key: VirtualKeyCode, key_press: Option<&mut KeyPress>) -> bool {
+pub fn check_press(keys: &[VirtualKeyCode], key_press: Option<&mut KeyPress>) -> bool {
     let input = rltk::INPUT.lock();
-    if input.is_key_pressed(key) {
+    if keys.iter().any(|key| input.is_key_pressed(*key)) {
         return if let Some(key_press) = key_press { can_press(key_press) } else { true };
     }
 
     if let Some(key_press) = key_press {
-        key_press.state = KeyState::RELEASED;
+        key_press.state = KeyState::Released;
     }
     false
 }
 
+/// Rltk's number for the right mouse button
+pub const RIGHT_MOUSE_BUTTON: usize = 1;
+
+/// Checks if a mouse button went down since the last check, so holding it only counts as one click.
+/// `held` remembers whether the button was down last time.
+pub fn check_click(button: usize, held: &mut bool) -> bool {
+    let pressed = rltk::INPUT.lock().is_mouse_button_pressed(button);
+    let clicked = pressed && !*held;
+    *held = pressed;
+    clicked
+}
+
 // Ideally this should be based on tick time, not system time!
 fn can_press(key: &mut KeyPress) -> bool {
     let current_time = SystemTime::now();
     if let Some(last_press_time) = key.last_press_time {
         let elapsed = current_time.duration_since(last_press_time).unwrap();
 
-        if key.state == KeyState::PRESSED {
+        if key.state == KeyState::Pressed {
             // Repeat delay
             if elapsed < Duration::from_millis(key.repeat_delay_ms) {
                 return false;
             } else {
-                key.state = KeyState::HOLD;
+                key.state = KeyState::Hold;
             }
         }
 
@@ -70,8 +82,8 @@ fn can_press(key: &mut KeyPress) -> bool {
     }
 
     key.last_press_time = Some(current_time);
-    if key.state == KeyState::RELEASED {
-        key.state = KeyState::PRESSED;
+    if key.state == KeyState::Released {
+        key.state = KeyState::Pressed;
     }
     true
 }
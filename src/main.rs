@@ -1,30 +1,48 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::collections::HashMap;
-
-use bounded_vec_deque::BoundedVecDeque;
-use rltk::{BResult, GameState, Point, RandomNumberGenerator, Rltk, RltkBuilder, VirtualKeyCode};
-use specs::{Join, RunNow, World, WorldExt};
-
-use crate::components::{BlocksTile, CombatStats, Consumable, InBackpack, InflictsDamage, Item, Monster, MovementSpeed, Name, Player, Position, ProvidesHealing, Ranged, Renderable, SerializationHelper, SerializeMe, SufferDamage, Viewshed, WantsToDropItem, WantsToMelee, WantsToPickupItem, WantsToUseItem};
-use crate::damage_system::DamageSystem;
-use crate::gamelog::GameLog;
-use crate::gui::{MainMenuResult, MainMenuSelection};
-use crate::inventory_system::{ItemCollectionSystem, ItemDropSystem, ItemUseSystem};
-use crate::keys_util::KeyPress;
+use rltk::{BEvent, BResult, GameState, Point, RandomNumberGenerator, Rltk, INPUT};
+use specs::{Entity, Join, RunNow, World, WorldExt};
+
+use crate::components::{BlocksTile, CombatStats, Consumable, Experience, HungerClock, NaturalAttack, Resistances, LootTable, SplitsOnDeath, ExplodesOnDeath, InBackpack, InflictsDamage, Item, Monster, MovementSpeed, Name, Player, Position, ProvidesFood, ProvidesHealing, Ranged, Renderable, SerializationHelper, SerializeMe, SufferDamage, ThrowingWeapon, Vendor, Viewshed, WantsToDropItem, WantsToMelee, WantsToPickupItem, WantsToThrowItem, WantsToTrade, WantsToUseItem, Gold, Container, InContainer, Key, Locked, WantsToOpen, Hidden, EntryTrigger, EntityMoved, Immobilizes, Immobilized, Teleports, MagicMapper, GrantsTelepathy, Telepathy, Description};
+use crate::command::Command;
+use crate::damage_system::{DamageSystem, DeathEvents};
+use crate::encumbrance_system::EncumbranceSystem;
+use crate::gamelog::{GameLog, LogCategory, LOG_CAPACITY};
+use crate::gui::{
+    ConfirmResult, ContextAction, ContextMenuResult, InventoryMode, LevelUpResult, LevelUpSelection, LogViewerResult,
+    LookResult, MainMenuResult, MainMenuSelection, OptionsResult, ShopMode, ShopResult, TargetAction, TargetingResult,
+    LootResult, LootSide,
+};
+use crate::hunger_system::HungerSystem;
+use crate::identification::Identification;
+use crate::inventory_system::{ItemCollectionSystem, ItemDropSystem, ItemThrowSystem, ItemUseSystem};
+use crate::key_bindings::KeyBindings;
 use crate::map::{draw_map, Map};
 use crate::map_indexing_system::MapIndexingSystem;
 use crate::melee_combat_system::MeleeCombatSystem;
 use crate::monster_ai_system::MonsterAI;
+use crate::movement_util::GameClock;
 use crate::player::player_input;
+use crate::replay::{Event, Playback, Recorder, Replay, ReplayError, ReplayMode};
+use crate::save_load_system::SaveInfo;
+use crate::settings::Settings;
+use crate::status_effect_system::StatusEffectSystem;
+use crate::travel::AutoMove;
+use crate::trigger_system::TriggerSystem;
 use crate::visibility_system::VisibilitySystem;
 use specs::saveload::{SimpleMarker, SimpleMarkerAllocator};
 
 mod components;
+mod command;
+mod containers;
 mod damage_system;
+mod encumbrance_system;
 mod gamelog;
 mod gui;
+mod hunger_system;
+mod identification;
 mod inventory_system;
+mod key_bindings;
 mod keys_util;
 mod map;
 mod map_indexing_system;
@@ -33,41 +51,180 @@ mod monster_ai_system;
 mod movement_util;
 mod player;
 mod rect;
+mod replay;
+mod settings;
 mod spawner;
+mod status_effect_system;
+mod travel;
+mod trigger_system;
 mod visibility_system;
 mod save_load_system;
+mod shop;
 
-macro_rules! hashmap {
-    ($( $key: expr => $val: expr ),*) => {{
-         let mut map = ::std::collections::HashMap::new();
-         $( map.insert($key, $val); )*
-         map
-    }}
-}
-
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Client {
     pub show_inventory: bool,
-    pub drop_inventory: bool,
-    pub keys: HashMap<VirtualKeyCode, KeyPress>,
-}
-
-impl Default for Client {
-    fn default() -> Self {
-        Client {
-            show_inventory: false,
-            drop_inventory: false,
-            keys: hashmap![VirtualKeyCode::E => KeyPress::new(100, 500), VirtualKeyCode::I => KeyPress::new(100, 500), VirtualKeyCode::Escape => KeyPress::new(100, 500), VirtualKeyCode::G => KeyPress::new(100, 500)],
-        }
-    }
+    pub inventory: gui::InventoryMenu,
+    pub keys: KeyBindings,
+    pub auto_move: Option<AutoMove>,
+    /// Whether the right mouse button was down last tick
+    pub right_button_held: bool,
+    pub settings: Settings,
+    /// A command performed from a menu took the player's turn, which is spent on the next tick
+    pub turn_pending: bool,
+    /// What the main menu knows about the save, read on the way into the menu rather than every frame
+    pub save_exists: bool,
+    pub save_info: Option<SaveInfo>,
 }
 
 pub struct State {
     ecs: World,
     client: Client,
+    replay: ReplayMode,
+}
+
+/// What the player's actions during a tick started, for the UI to react to
+struct StepOutcome {
+    /// Shopkeeper the player bumped into
+    trade: Option<Entity>,
+    /// Container the player bumped into and managed to open
+    opened: Option<Entity>,
+    magic_map: bool,
+    levels_gained: i32,
 }
 
 impl State {
+    /// Carries out a player command, recording it if the run is being recorded. Returns true if it
+    /// took the player's turn.
+    fn perform(&mut self, command: Command) -> bool {
+        if let ReplayMode::Recording(recorder) = &mut self.replay {
+            recorder.command(&command);
+        }
+        let took_turn = command::apply(&mut self.ecs, &command);
+        self.client.turn_pending |= took_turn;
+        took_turn
+    }
+
+    /// Writes out the recording if the run is being recorded, and stops recording
+    fn finish_recording(&mut self) {
+        if let ReplayMode::Recording(mut recorder) = std::mem::take(&mut self.replay) {
+            let message = match recorder.finish(&self.ecs) {
+                Ok(turns) => format!("Recorded {} turns to {}.", turns, recorder.path()),
+                Err(e) => e.to_string(),
+            };
+            self.ecs.fetch_mut::<GameLog>().add(LogCategory::System, message);
+        }
+    }
+
+    /// Throws away the current run and starts a fresh one, keeping the player's settings
+    fn new_game(&mut self) {
+        self.start_run(new_world(RandomNumberGenerator::new().next_u64()));
+    }
+
+    /// Throws away the current run and carries on the saved one, leaving the current run alone if the
+    /// save can't be loaded
+    fn load_game(&mut self) -> serde_json::Result<()> {
+        let mut world = new_world(RandomNumberGenerator::new().next_u64());
+        save_load_system::load_game(&mut world)?;
+        // The recording can't follow into a game that didn't start from its seed
+        self.finish_recording();
+        self.start_run(world);
+        Ok(())
+    }
+
+    fn start_run(&mut self, world: World) {
+        self.ecs = world;
+        self.ecs.fetch_mut::<GameLog>().set_capacity(self.client.settings.log_length);
+        self.client = Client {
+            keys: std::mem::take(&mut self.client.keys),
+            settings: std::mem::take(&mut self.client.settings),
+            ..Client::default()
+        };
+        self.read_save_info();
+    }
+
+    /// Looks at the save again, after it might have changed
+    fn read_save_info(&mut self) {
+        self.client.save_exists = save_load_system::does_save_exist();
+        self.client.save_info = save_load_system::save_info();
+    }
+
+    /// Puts the client's settings into effect, apart from the font which needs a restart
+    fn apply_settings(&mut self, ctx: &mut Rltk) {
+        self.client.settings.apply_scanlines(ctx);
+        self.client.keys = load_key_bindings(&self.client.settings);
+        self.ecs.fetch_mut::<GameLog>().set_capacity(self.client.settings.log_length);
+    }
+
+    /// Runs the simulation for a tick of `frame_ms` game time
+    fn step(&mut self, frame_ms: u64, took_turn: bool) -> StepOutcome {
+        if let ReplayMode::Recording(recorder) = &mut self.replay {
+            recorder.tick(frame_ms, took_turn);
+        }
+        let turn = {
+            let mut clock = self.ecs.fetch_mut::<GameClock>();
+            clock.elapsed_ms += frame_ms;
+            clock.turn += took_turn as u64;
+            clock.turn
+        };
+        self.ecs.fetch_mut::<GameLog>().turn = turn;
+
+        // Systems need to know whether the player spent a turn this tick
+        *self.ecs.write_resource::<RunState>() = if took_turn { RunState::PlayerTurn } else { RunState::Running };
+        self.run_systems();
+        // Reading a magic mapping scroll starts the reveal
+        let magic_map = matches!(*self.ecs.fetch::<RunState>(), RunState::MagicMapReveal { .. });
+
+        let player_entity = *self.ecs.fetch::<Entity>();
+        let trade = self.ecs.write_storage::<WantsToTrade>().remove(player_entity).map(|trade| trade.vendor);
+        let open = self.ecs.write_storage::<WantsToOpen>().remove(player_entity);
+        let opened =
+            open.map(|open| open.container).filter(|container| containers::try_open(&mut self.ecs, *container));
+
+        let levels_gained = damage_system::delete_the_dead(&mut self.ecs);
+        StepOutcome { trade, opened, magic_map, levels_gained }
+    }
+
+    /// Plays back recorded commands up to and including the next tick, returning false once the
+    /// recording has run out
+    fn replay_tick(&mut self) -> bool {
+        let mut took_turn = false;
+        loop {
+            let ReplayMode::Playing(playback) = &mut self.replay else {
+                return false;
+            };
+            match playback.next_event() {
+                None => return false,
+                Some(Event::Command { turn, command }) => {
+                    playback.check_turn(turn, self.ecs.fetch::<GameClock>().turn);
+                    took_turn |= command::apply(&mut self.ecs, &command);
+                }
+                Some(Event::Tick { frame_ms }) => {
+                    // Menus don't open during a replay, so the map is revealed all at once
+                    if self.step(frame_ms, took_turn).magic_map {
+                        self.ecs.fetch_mut::<Map>().revealed_tiles.iter_mut().for_each(|tile| *tile = true);
+                    }
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Logs whether a finished replay ended up the same as its recording, and hands control back to
+    /// the player
+    fn finish_replay(&mut self) -> Result<(), ReplayError> {
+        let ReplayMode::Playing(playback) = std::mem::take(&mut self.replay) else {
+            return Ok(());
+        };
+        let result = playback.verify(&self.ecs);
+        let message = match &result {
+            Ok(()) => "Replay finished, the game matches the recording.".to_string(),
+            Err(e) => e.to_string(),
+        };
+        self.ecs.fetch_mut::<GameLog>().add(LogCategory::System, message);
+        result
+    }
+
     fn run_systems(&mut self) {
         let mut vis = VisibilitySystem {};
         vis.run_now(&self.ecs);
@@ -75,18 +232,60 @@ impl State {
         mob.run_now(&self.ecs);
         let mut mapindex = MapIndexingSystem {};
         mapindex.run_now(&self.ecs);
+        let mut triggers = TriggerSystem {};
+        triggers.run_now(&self.ecs);
         let mut melee_combat = MeleeCombatSystem {};
         melee_combat.run_now(&self.ecs);
-        let mut damage = DamageSystem {};
-        damage.run_now(&self.ecs);
+        let mut hunger = HungerSystem {};
+        hunger.run_now(&self.ecs);
+        let mut status_effects = StatusEffectSystem {};
+        status_effects.run_now(&self.ecs);
         let mut inventory = ItemCollectionSystem {};
         inventory.run_now(&self.ecs);
         let mut items = ItemUseSystem {};
         items.run_now(&self.ecs);
+        let mut throws = ItemThrowSystem {};
+        throws.run_now(&self.ecs);
+        // Last, so potions and thrown weapons hurt on the turn they're used
+        let mut damage = DamageSystem {};
+        damage.run_now(&self.ecs);
         let mut drops = ItemDropSystem {};
         drops.run_now(&self.ecs);
+        let mut encumbrance = EncumbranceSystem {};
+        encumbrance.run_now(&self.ecs);
         self.ecs.maintain();
     }
+
+    /// Draws the map, everything visible on it and the UI
+    fn draw_game(&mut self, ctx: &mut Rltk) {
+        draw_map(&self.ecs, ctx);
+
+        // Renderables
+        {
+            let positions = self.ecs.read_storage::<Position>();
+            let renderables = self.ecs.read_storage::<Renderable>();
+            let hidden = self.ecs.read_storage::<Hidden>();
+            let monsters = self.ecs.read_storage::<Monster>();
+            let map = self.ecs.fetch::<Map>();
+            // Telepathy shows monsters wherever they are
+            let player_entity = *self.ecs.fetch::<Entity>();
+            let telepathic = self.ecs.read_storage::<Telepathy>().contains(player_entity);
+
+            let mut data = (&self.ecs.entities(), &positions, &renderables, !&hidden)
+                .join()
+                .map(|(e, p, r, _)| (e, p, r))
+                .collect::<Vec<_>>();
+            data.sort_by_key(|&a| std::cmp::Reverse(a.2.render_order));
+            for (entity, pos, render) in data.iter() {
+                let idx = map.xy_idx(pos.x, pos.y);
+                if map.visible_tiles[idx] || (telepathic && monsters.contains(*entity)) {
+                    ctx.set(pos.x, pos.y, render.fg, render.bg, render.glyph);
+                }
+            }
+        }
+
+        gui::draw_ui(self, ctx);
+    }
 }
 
 impl GameState for State {
@@ -98,6 +297,15 @@ impl GameState for State {
         }
         
         ctx.cls();
+
+        // Closing the window ends the main loop without another tick, so the recording is saved first
+        while let Some(event) = INPUT.lock().pop() {
+            if matches!(event, BEvent::CloseRequested) {
+                self.finish_recording();
+                ctx.quit();
+                return;
+            }
+        }
         
         match newrunstate {
             RunState::MainMenu {..} => {
@@ -108,46 +316,221 @@ impl GameState for State {
                     }
                     MainMenuResult::Selected { selected } => {
                         match selected {
+                            MainMenuSelection::NewGame if self.client.save_exists => {
+                                newrunstate = RunState::ConfirmNewGame;
+                            }
                             MainMenuSelection::NewGame => {
-                                println!("new game");
+                                self.new_game();
+                                newrunstate = RunState::Running;
                             }
-                            MainMenuSelection::LoadGame => {
-                                println!("load game");
+                            MainMenuSelection::LoadGame => match self.load_game() {
+                                Ok(()) => newrunstate = RunState::Running,
+                                Err(e) => println!("Could not load {}: {}", save_load_system::SAVE_PATH, e),
+                            },
+                            MainMenuSelection::Options => newrunstate = RunState::Options { selection: 0 },
+                            MainMenuSelection::Quit => {
+                                self.finish_recording();
+                                std::process::exit(0);
                             }
-                            MainMenuSelection::Quit => { std::process::exit(0); }
                         }
                     }
                 }
             },
+            RunState::Running if matches!(self.replay, ReplayMode::Playing(_)) => {
+                if !self.replay_tick() {
+                    // The outcome is in the log
+                    let _ = self.finish_replay();
+                }
+                self.draw_game(ctx);
+            },
             RunState::Running => {
-                newrunstate = player_input(self);
-                self.run_systems();
-
-                damage_system::delete_the_dead(&mut self.ecs);
+                newrunstate = player_input(self, ctx);
+                let took_turn = newrunstate == RunState::PlayerTurn || std::mem::take(&mut self.client.turn_pending);
+                if took_turn {
+                    newrunstate = RunState::Running;
+                }
+                let outcome = self.step(ctx.frame_time_ms.round() as u64, took_turn);
+                if outcome.magic_map {
+                    newrunstate = RunState::MagicMapReveal { row: 0 };
+                }
+                // Bumping into a shopkeeper opens their shop
+                if let Some(vendor) = outcome.trade {
+                    self.client.show_inventory = false;
+                    newrunstate = RunState::Shop { vendor, mode: ShopMode::Buy, selection: 0 };
+                }
+                if let Some(container) = outcome.opened {
+                    self.client.show_inventory = false;
+                    newrunstate = RunState::LootContainer { container, side: LootSide::Container, selection: 0 };
+                }
+                let levels_gained = outcome.levels_gained;
+                // Nothing more can happen once the player is dead, so the recording is complete
+                let player_entity = *self.ecs.fetch::<Entity>();
+                if self.ecs.read_storage::<CombatStats>().get(player_entity).is_some_and(|stats| stats.hp <= 0) {
+                    self.finish_recording();
+                }
 
-                draw_map(&self.ecs, ctx);
+                self.draw_game(ctx);
+                if let Some(state) = gui::show_inventory(self, ctx) {
+                    newrunstate = state;
+                }
 
-                // Renderables
+                // Pause the game until the player has picked their level up bonuses
+                if levels_gained > 0 {
+                    self.client.show_inventory = false;
+                    self.client.inventory.reset_mode();
+                    newrunstate =
+                        RunState::LevelUp { menu_selection: LevelUpSelection::Vitality, remaining: levels_gained };
+                }
+            },
+            RunState::LevelUp { remaining, .. } => {
+                self.draw_game(ctx);
+                let result = gui::level_up_menu(self, ctx);
+                match result {
+                    LevelUpResult::NoSelection { selected } => {
+                        newrunstate = RunState::LevelUp { menu_selection: selected, remaining }
+                    }
+                    LevelUpResult::Selected { selected } => {
+                        self.perform(Command::LevelUp(selected));
+                        newrunstate = if remaining > 1 {
+                            RunState::LevelUp { menu_selection: selected, remaining: remaining - 1 }
+                        } else {
+                            RunState::Running
+                        };
+                    }
+                }
+            },
+            RunState::ShowTargeting { range, item, action } => {
+                self.draw_game(ctx);
+                match gui::ranged_target(self, ctx, range) {
+                    TargetingResult::Cancel => newrunstate = RunState::Running,
+                    TargetingResult::NoResponse => {}
+                    TargetingResult::Selected(target) => {
+                        let item = item.id();
+                        self.perform(match action {
+                            TargetAction::Use => Command::UseItem { item, target: Some(target) },
+                            TargetAction::Throw => Command::ThrowItem { item, target },
+                        });
+                        newrunstate = RunState::Running;
+                    }
+                }
+            },
+            RunState::Shop { vendor, mode, selection } => {
+                self.draw_game(ctx);
+                match gui::show_shop(self, ctx, vendor, mode, selection) {
+                    ShopResult::Close => newrunstate = RunState::Running,
+                    ShopResult::NoSelection { mode, selection } => {
+                        newrunstate = RunState::Shop { vendor, mode, selection }
+                    }
+                    ShopResult::Selected { mode, selection, item } => {
+                        let (vendor_id, item) = (vendor.id(), item.id());
+                        self.perform(match mode {
+                            ShopMode::Buy => Command::Buy { vendor: vendor_id, item },
+                            ShopMode::Sell => Command::Sell { vendor: vendor_id, item },
+                        });
+                        newrunstate = RunState::Shop { vendor, mode, selection }
+                    }
+                }
+            },
+            RunState::LootContainer { container, side, selection } => {
+                self.draw_game(ctx);
+                match gui::show_container(self, ctx, container, side, selection) {
+                    LootResult::Close => newrunstate = RunState::Running,
+                    LootResult::NoSelection { side, selection } => {
+                        newrunstate = RunState::LootContainer { container, side, selection }
+                    }
+                    LootResult::Selected { side, selection, item } => {
+                        let (container_id, item) = (container.id(), item.id());
+                        self.perform(match side {
+                            LootSide::Container => Command::Take { container: container_id, item },
+                            LootSide::Backpack => Command::Put { container: container_id, item },
+                        });
+                        newrunstate = RunState::LootContainer { container, side, selection }
+                    }
+                }
+            },
+            RunState::MagicMapReveal { row } => {
+                // One row per tick, so the map sweeps into view
                 {
-                    let positions = self.ecs.read_storage::<Position>();
-                    let renderables = self.ecs.read_storage::<Renderable>();
-                    let map = self.ecs.fetch::<Map>();
-
-                    let mut data = (&positions, &renderables).join().collect::<Vec<_>>();
-                    data.sort_by(|&a, &b| b.1.render_order.cmp(&a.1.render_order));
-                    for (pos, render) in data.iter() {
-                        let idx = map.xy_idx(pos.x, pos.y);
-                        if map.visible_tiles[idx] {
-                            ctx.set(pos.x, pos.y, render.fg, render.bg, render.glyph);
+                    let mut map = self.ecs.fetch_mut::<Map>();
+                    for x in 0..map.width {
+                        let idx = map.xy_idx(x, row);
+                        map.revealed_tiles[idx] = true;
+                    }
+                }
+                self.draw_game(ctx);
+                let height = self.ecs.fetch::<Map>().height;
+                newrunstate = if row + 1 < height {
+                    RunState::MagicMapReveal { row: row + 1 }
+                } else {
+                    RunState::Running
+                };
+            },
+            RunState::ContextMenu { target, selection } => {
+                self.draw_game(ctx);
+                match gui::show_context_menu(self, ctx, target, selection) {
+                    ContextMenuResult::Close => newrunstate = RunState::Running,
+                    ContextMenuResult::NoSelection { selection } => {
+                        newrunstate = RunState::ContextMenu { target, selection }
+                    }
+                    ContextMenuResult::Selected(action) => {
+                        match action {
+                            ContextAction::Examine => player::examine(&self.ecs, target),
+                            ContextAction::PickUp => {
+                                self.perform(Command::PickUp);
+                            }
+                            ContextAction::ThrowAt => {
+                                self.client.show_inventory = true;
+                                self.client.inventory.mode = InventoryMode::Throw;
+                                self.client.inventory.target = Some(target);
+                            }
                         }
+                        newrunstate = RunState::Running;
                     }
                 }
-
-                gui::draw_ui(self, ctx);
             },
+            RunState::LogViewer { offset, filter } => match gui::show_log_viewer(self, ctx, offset, filter) {
+                LogViewerResult::Close => newrunstate = RunState::Running,
+                LogViewerResult::NoResponse { offset, filter } => newrunstate = RunState::LogViewer { offset, filter },
+            },
+            RunState::ConfirmNewGame => match gui::confirm_new_game(ctx) {
+                ConfirmResult::Yes => {
+                    self.new_game();
+                    newrunstate = RunState::Running;
+                }
+                ConfirmResult::No => newrunstate = RunState::MainMenu { menu_selection: MainMenuSelection::NewGame },
+                ConfirmResult::NoResponse => {}
+            },
+            RunState::Options { selection } => match gui::show_options(self, ctx, selection) {
+                OptionsResult::NoSelection { selection } => newrunstate = RunState::Options { selection },
+                OptionsResult::Changed { selection } => {
+                    self.apply_settings(ctx);
+                    newrunstate = RunState::Options { selection };
+                }
+                OptionsResult::Close => {
+                    if let Err(e) = self.client.settings.save(settings::SETTINGS_PATH) {
+                        println!("Could not save settings to {}: {}", settings::SETTINGS_PATH, e);
+                    }
+                    newrunstate = RunState::MainMenu { menu_selection: MainMenuSelection::Options };
+                }
+            },
+            RunState::LookMode { cursor } => {
+                self.draw_game(ctx);
+                newrunstate = match gui::show_look_mode(self, ctx, cursor) {
+                    LookResult::Close => RunState::Running,
+                    LookResult::NoResponse { cursor } => RunState::LookMode { cursor },
+                };
+            }
+            RunState::CharacterSheet => {
+                self.draw_game(ctx);
+                if gui::show_character_sheet(self, ctx) {
+                    newrunstate = RunState::Running;
+                }
+            }
             RunState::SaveGame => {
+                self.finish_recording();
                 println!("Saving game");
-                saveload_system::save_game(&mut self.ecs);
+                save_load_system::save_game(&mut self.ecs);
+                self.read_save_info();
                 newrunstate = RunState::MainMenu { menu_selection: MainMenuSelection::LoadGame };
             }
             _ => {
@@ -163,22 +546,134 @@ impl GameState for State {
 pub enum RunState {
     Paused,
     Running,
+    /// The player acted this tick, so turn-based systems should advance
+    PlayerTurn,
     SaveGame,
+    /// Waiting for the player to choose a bonus for each of the `remaining` levels they gained
+    LevelUp { menu_selection: gui::LevelUpSelection, remaining: i32 },
+    /// Picking a tile to use or throw an item at, within `range` of the player
+    ShowTargeting { range: i32, item: Entity, action: gui::TargetAction },
+    /// Trading with a vendor
+    Shop { vendor: Entity, mode: gui::ShopMode, selection: usize },
+    /// Moving items between a container and the player's backpack
+    LootContainer { container: Entity, side: gui::LootSide, selection: usize },
+    /// Revealing the map a row at a time after reading a magic mapping scroll
+    MagicMapReveal { row: i32 },
+    /// Picking what to do with a right-clicked tile
+    ContextMenu { target: Point, selection: usize },
+    /// Reading back through the message log, `offset` messages from the newest
+    LogViewer { offset: usize, filter: Option<LogCategory> },
+    /// Inspecting whatever is under a cursor moved around the map
+    LookMode { cursor: Point },
+    CharacterSheet,
+    Options { selection: usize },
+    /// Checking the player is happy to start a new game over their save
+    ConfirmNewGame,
     MainMenu { menu_selection: gui::MainMenuSelection }
 }
 
 fn main() -> BResult<()> {
-    let mut context = RltkBuilder::simple80x50().with_title("Roguelike Tutorial").build()?;
-    context.with_post_scanlines(true);
+    // Runs can be recorded with --record <file>, and played back with --replay <file>, optionally
+    // without a window using --headless
+    let args: Vec<String> = std::env::args().collect();
+    let arg_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1)).cloned();
+    let replay = arg_value("--replay")
+        .map(|path| Replay::load(&path).unwrap_or_else(|e| panic!("Could not load replay {}: {}", path, e)));
+    let seed = replay.as_ref().map_or_else(|| RandomNumberGenerator::new().next_u64(), |replay| replay.seed);
+
+    let settings = Settings::load(settings::SETTINGS_PATH).unwrap_or_else(|e| {
+        println!("Using default settings, could not load {}: {}", settings::SETTINGS_PATH, e);
+        Settings::default()
+    });
+    let mut gs = State {
+        ecs: new_world(seed),
+        client: Client { keys: load_key_bindings(&settings), settings, ..Client::default() },
+        replay: match (replay, arg_value("--record")) {
+            (Some(replay), _) => ReplayMode::Playing(Playback::new(replay)),
+            (None, Some(path)) => ReplayMode::Recording(Recorder::new(&path, seed)),
+            (None, None) => ReplayMode::Off,
+        },
+    };
+    gs.read_save_info();
 
-    // World
+    if args.iter().any(|arg| arg == "--headless") && matches!(gs.replay, ReplayMode::Playing(_)) {
+        while gs.replay_tick() {}
+        if let Err(e) = gs.finish_replay() {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    gs.ecs.fetch_mut::<GameLog>().set_capacity(gs.client.settings.log_length);
+    let mut context = gs.client.settings.window().with_title("Roguelike Tutorial").build()?;
+    // Lets the window close be seen in time to save a recording
+    INPUT.lock().activate_event_queue();
+    gs.client.settings.apply_scanlines(&mut context);
+    rltk::main_loop(context, gs)
+}
+
+/// Loads the key bindings, falling back to the defaults if the file is broken
+fn load_key_bindings(settings: &Settings) -> KeyBindings {
+    KeyBindings::load(key_bindings::KEY_BINDINGS_PATH, settings.key_repeat_delay_ms).unwrap_or_else(|e| {
+        println!("Using default key bindings, {}", e);
+        KeyBindings::with_repeat_delay(settings.key_repeat_delay_ms)
+    })
+}
+
+/// Registers all the components and generates a new run from the seed
+fn new_world(seed: u64) -> World {
     let mut world = World::new();
+    register_components(&mut world);
+
+    // RNG, seeded so the run can be replayed
+    let mut rng = RandomNumberGenerator::seeded(seed);
+    // Unidentified item names are part of the run, so come from its RNG
+    world.insert(Identification::new(&mut rng));
+    let map = Map::new_map_rooms_and_corridors(&mut rng);
+    world.insert(rng);
+    // Deaths waiting to be cleaned up by delete_the_dead
+    world.insert(DeathEvents::default());
+    world.insert(GameClock::default());
+
+    let (player_x, player_y) = map.rooms[0].center();
+
+    // Player
+    let player_entity = spawner::player(&mut world, player_x, player_y);
+    // Add the player as an Entity resource itself so it can be referenced from everywhere
+    world.insert(player_entity);
+
+    // Monsters, leaving the last room to the shopkeeper
+    let shop_room = map.rooms.len() - 1;
+    for room in map.rooms.iter().take(shop_room).skip(1) {
+        spawner::spawn_room(&mut world, room);
+    }
+    let (shop_x, shop_y) = map.rooms[shop_room].center();
+    spawner::shopkeeper(&mut world, shop_x, shop_y);
+
+    // Map
+    world.insert(map);
+    // Player position as a resource since it's used often
+    world.insert(Point::new(player_x, player_y));
+    // Game logs
+    let mut log = GameLog::new(LOG_CAPACITY);
+    log.add(LogCategory::System, "Welcome to spoorn's dungeon (:<");
+    world.insert(log);
+    
+    // RunState
+    world.insert(RunState::Running);
+    world
+}
+
+/// Registers every component, so a world can hold anything that gets spawned or loaded
+fn register_components(world: &mut World) {
     world.register::<Position>();
     world.register::<Renderable>();
     world.register::<Player>();
     world.register::<Viewshed>();
     world.register::<Monster>();
     world.register::<Name>();
+    world.register::<Description>();
     world.register::<MovementSpeed>();
     world.register::<BlocksTile>();
     world.register::<CombatStats>();
@@ -191,46 +686,38 @@ fn main() -> BResult<()> {
     world.register::<WantsToUseItem>();
     world.register::<Consumable>();
     world.register::<WantsToDropItem>();
+    world.register::<WantsToThrowItem>();
+    world.register::<ThrowingWeapon>();
+    world.register::<Gold>();
+    world.register::<Vendor>();
+    world.register::<WantsToTrade>();
+    world.register::<Container>();
+    world.register::<Locked>();
+    world.register::<Key>();
+    world.register::<InContainer>();
+    world.register::<WantsToOpen>();
+    world.register::<Hidden>();
+    world.register::<EntryTrigger>();
+    world.register::<EntityMoved>();
+    world.register::<Immobilizes>();
+    world.register::<Immobilized>();
+    world.register::<Teleports>();
+    world.register::<MagicMapper>();
+    world.register::<GrantsTelepathy>();
+    world.register::<Telepathy>();
     world.register::<Ranged>();
     world.register::<InflictsDamage>();
+    world.register::<HungerClock>();
+    world.register::<Experience>();
+    world.register::<NaturalAttack>();
+    world.register::<Resistances>();
+    world.register::<LootTable>();
+    world.register::<SplitsOnDeath>();
+    world.register::<ExplodesOnDeath>();
+    world.register::<ProvidesFood>();
     world.register::<SerializationHelper>();
     
     // Serializing entities
     world.register::<SimpleMarker<SerializeMe>>();
     world.insert(SimpleMarkerAllocator::<SerializeMe>::new());
-
-    // RNG
-    world.insert(RandomNumberGenerator::new());
-
-    let map = Map::new_map_rooms_and_corridors();
-    let (player_x, player_y) = map.rooms[0].center();
-
-    // Player
-    let player_entity = spawner::player(&mut world, player_x, player_y);
-    // Add the player as an Entity resource itself so it can be referenced from everywhere
-    world.insert(player_entity);
-
-    // Monsters
-    for (_i, room) in map.rooms.iter().skip(1).enumerate() {
-        spawner::spawn_room(&mut world, room);
-    }
-
-    // Map
-    world.insert(map);
-    // Player position as a resource since it's used often
-    world.insert(Point::new(player_x, player_y));
-    // Game logs
-    let mut entries = BoundedVecDeque::new(127);
-    entries.push_back("Welcome to spoorn's dungeon (:<".to_string());
-    world.insert(GameLog { entries });
-    
-    // RunState
-    world.insert(RunState::Running);
-
-    // GameState
-    let gs = State {
-        ecs: world,
-        client: Client::default(),
-    };
-    rltk::main_loop(context, gs)
 }
@@ -36,6 +36,11 @@ impl Map {
         (y * self.width) as usize + x as usize
     }
 
+    /// True if the player can currently see the tile at the given position
+    pub fn is_visible(&self, x: i32, y: i32) -> bool {
+        x >= 0 && x < self.width && y >= 0 && y < self.height && self.visible_tiles[self.xy_idx(x, y)]
+    }
+
     pub fn populate_blocked(&mut self) {
         for (i, tile) in self.tiles.iter_mut().enumerate() {
             self.blocked[i] = *tile == TileType::Wall;
@@ -50,7 +55,7 @@ impl Map {
 
     /// Makes a new map using the algorithm from http://rogueliketutorials.com/tutorials/tcod/part-3/
     /// This gives a handful of random rooms and corridors joining them together.
-    pub fn new_map_rooms_and_corridors() -> Map {
+    pub fn new_map_rooms_and_corridors(rng: &mut RandomNumberGenerator) -> Map {
         let mut map = Map {
             tiles: vec![TileType::Wall; MAP_COUNT],
             rooms: vec![],
@@ -66,7 +71,6 @@ impl Map {
         const MIN_SIZE: i32 = 6;
         const MAX_SIZE: i32 = 10;
 
-        let mut rng = RandomNumberGenerator::new();
         'outer: for _ in 0..MAX_ROOMS {
             let w = rng.range(MIN_SIZE, MAX_SIZE);
             let h = rng.range(MIN_SIZE, MAX_SIZE);
@@ -111,7 +115,7 @@ impl Map {
         for x in min(x1, x2)..max(x1, x2) + 1 {
             let idx = self.xy_idx(x, y);
             if idx > 0 && idx < self.tiles.len() {
-                self.tiles[idx as usize] = TileType::Floor;
+                self.tiles[idx] = TileType::Floor;
             }
         }
     }
@@ -120,7 +124,7 @@ impl Map {
         for y in min(y1, y2)..=max(y1, y2) {
             let idx = self.xy_idx(x, y);
             if idx > 0 && idx < self.tiles.len() {
-                self.tiles[idx as usize] = TileType::Floor;
+                self.tiles[idx] = TileType::Floor;
             }
         }
     }
@@ -133,6 +137,11 @@ impl Map {
         let idx = self.xy_idx(x, y);
         !self.blocked[idx]
     }
+
+    /// Diagonal steps can't squeeze past a corner, so both tiles beside the step have to be free
+    pub fn cuts_corner(&self, x: i32, y: i32, delta_x: i32, delta_y: i32) -> bool {
+        delta_x != 0 && delta_y != 0 && (!self.is_exit_valid(x + delta_x, y) || !self.is_exit_valid(x, y + delta_y))
+    }
 }
 
 impl BaseMap for Map {
@@ -161,16 +170,16 @@ impl BaseMap for Map {
         };
 
         // Diagonals
-        if self.is_exit_valid(x - 1, y - 1) {
+        if self.is_exit_valid(x - 1, y - 1) && !self.cuts_corner(x, y, -1, -1) {
             exits.push(((idx - w) - 1, 1.45));
         }
-        if self.is_exit_valid(x + 1, y - 1) {
+        if self.is_exit_valid(x + 1, y - 1) && !self.cuts_corner(x, y, 1, -1) {
             exits.push(((idx - w) + 1, 1.45));
         }
-        if self.is_exit_valid(x - 1, y + 1) {
+        if self.is_exit_valid(x - 1, y + 1) && !self.cuts_corner(x, y, -1, 1) {
             exits.push(((idx + w) - 1, 1.45));
         }
-        if self.is_exit_valid(x + 1, y + 1) {
+        if self.is_exit_valid(x + 1, y + 1) && !self.cuts_corner(x, y, 1, 1) {
             exits.push(((idx + w) + 1, 1.45));
         }
 
@@ -223,3 +232,54 @@ pub fn draw_map(ecs: &World, ctx: &mut Rltk) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A room with floor from (1, 1) to (5, 5) and a single pillar at (3, 3)
+    fn room_with_pillar() -> Map {
+        let mut map = Map {
+            tiles: vec![TileType::Wall; MAP_COUNT],
+            rooms: vec![],
+            width: MAP_WIDTH as i32,
+            height: MAP_HEIGHT as i32,
+            revealed_tiles: vec![false; MAP_COUNT],
+            visible_tiles: vec![false; MAP_COUNT],
+            blocked: vec![false; MAP_COUNT],
+            tile_content: vec![Vec::new(); MAP_COUNT],
+        };
+        map.apply_room_to_map(&Rect::new(0, 0, 5, 5));
+        let pillar = map.xy_idx(3, 3);
+        map.tiles[pillar] = TileType::Wall;
+        map.populate_blocked();
+        map
+    }
+
+    #[test]
+    fn diagonal_steps_cannot_squeeze_past_a_wall() {
+        let map = room_with_pillar();
+        assert!(map.cuts_corner(2, 3, 1, -1));
+        assert!(map.cuts_corner(3, 4, 1, -1));
+        assert!(!map.cuts_corner(4, 4, -1, 1));
+    }
+
+    #[test]
+    fn straight_steps_never_cut_corners() {
+        let map = room_with_pillar();
+        assert!(!map.cuts_corner(2, 3, 1, 0));
+        assert!(!map.cuts_corner(3, 2, 0, 1));
+    }
+
+    #[test]
+    fn paths_go_around_the_pillar_without_cutting_it() {
+        let map = room_with_pillar();
+        let path = rltk::a_star_search(map.xy_idx(2, 3), map.xy_idx(4, 3), &map);
+        assert!(path.success);
+        for step in path.steps.windows(2) {
+            let (from, to) = (step[0] as i32, step[1] as i32);
+            let (x, y) = (from % map.width, from / map.width);
+            assert!(!map.cuts_corner(x, y, to % map.width - x, to / map.width - y));
+        }
+    }
+}
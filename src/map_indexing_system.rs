@@ -14,7 +14,7 @@ impl<'a> System<'a> for MapIndexingSystem {
             let idx = map.xy_idx(position.x, position.y);
 
             // If they block, update the blocking list
-            if let Some(_) = blockers.get(entity) {
+            if blockers.get(entity).is_some() {
                 map.blocked[idx] = true;
             }
 
@@ -1,32 +1,156 @@
+use rltk::{DiceType, RandomNumberGenerator};
 use specs::{Entities, Join, ReadStorage, System, WriteExpect, WriteStorage};
 
+use crate::components::{DamageType, HungerClock, NaturalAttack, Resistances};
+use crate::damage_system::resisted_damage;
+use crate::gamelog::{LogCategory, LogLine, AMOUNT_COLOR, NAME_COLOR};
+use crate::hunger_system::power_modifier;
 use crate::{CombatStats, GameLog, Name, SufferDamage, WantsToMelee};
 
+/// Base armour class before adding the defender's defense
+const BASE_ARMOUR_CLASS: i32 = 10;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum AttackOutcome {
+    /// A natural 1, which always misses
+    Fumble,
+    Miss,
+    Hit(i32),
+    /// A natural 20, which always hits and rolls the damage dice twice
+    Critical(i32),
+}
+
+pub fn armour_class(defense: i32) -> i32 {
+    BASE_ARMOUR_CLASS + defense
+}
+
+/// Rolls a d20 plus the attack bonus against the armour class, then rolls damage on a hit.
+///
+/// The attacker's power only goes into the attack bonus, so it makes hits more likely but not
+/// harder. How much a hit does is down to the damage dice of the weapon or natural attack, which
+/// replaced the old `power - defense` damage.
+pub fn resolve_attack(
+    rng: &mut RandomNumberGenerator,
+    attack_bonus: i32,
+    damage: DiceType,
+    armour_class: i32,
+) -> AttackOutcome {
+    let natural_roll = rng.roll_dice(1, 20);
+    match natural_roll {
+        1 => AttackOutcome::Fumble,
+        20 => {
+            let dice = rng.roll_dice(damage.n_dice * 2, damage.die_type);
+            AttackOutcome::Critical(i32::max(0, dice + damage.bonus))
+        }
+        _ if natural_roll + attack_bonus >= armour_class => AttackOutcome::Hit(i32::max(0, rng.roll(damage))),
+        _ => AttackOutcome::Miss,
+    }
+}
+
+/// Notes in the combat log when resistances changed how much damage landed
+pub fn resistance_note(rolled: i32, landed: i32) -> String {
+    match landed.cmp(&rolled) {
+        std::cmp::Ordering::Less => format!(" ({} resisted)", rolled - landed),
+        std::cmp::Ordering::Greater => format!(" ({} vulnerable)", landed - rolled),
+        std::cmp::Ordering::Equal => String::new(),
+    }
+}
+
 pub struct MeleeCombatSystem {}
 
 impl<'a> System<'a> for MeleeCombatSystem {
     type SystemData = (
         Entities<'a>,
         WriteExpect<'a, GameLog>,
+        WriteExpect<'a, RandomNumberGenerator>,
         WriteStorage<'a, WantsToMelee>,
         ReadStorage<'a, Name>,
         ReadStorage<'a, CombatStats>,
         WriteStorage<'a, SufferDamage>,
+        ReadStorage<'a, HungerClock>,
+        ReadStorage<'a, NaturalAttack>,
+        ReadStorage<'a, Resistances>,
     );
 
-    fn run(&mut self, (entities, mut log, mut wants_melee, names, combat_stats, mut suffer_damage): Self::SystemData) {
-        for (_entity, wants_melee, name, stats) in (&entities, &wants_melee, &names, &combat_stats).join() {
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut log,
+            mut rng,
+            mut wants_melee,
+            names,
+            combat_stats,
+            mut suffer_damage,
+            hunger_clock,
+            natural_attacks,
+            resistances,
+        ): Self::SystemData,
+    ) {
+        for (entity, wants_melee, name, stats) in (&entities, &wants_melee, &names, &combat_stats).join() {
             if stats.hp > 0 {
                 let target_stats = combat_stats.get(wants_melee.target).unwrap();
                 if target_stats.hp > 0 {
                     let target_name = names.get(wants_melee.target).unwrap();
-                    let damage = i32::max(0, stats.power - target_stats.defense);
+                    // Anything without a natural attack just flails for 1d4
+                    let (attack_name, hit_bonus, damage_dice, damage_type) = match natural_attacks.get(entity) {
+                        Some(attack) => (attack.name.as_str(), attack.hit_bonus, attack.damage, attack.damage_type),
+                        None => ("bare hands", 0, DiceType::default(), DamageType::Bludgeoning),
+                    };
+                    // Power helps land the blow, the dice decide how much it hurts
+                    let attack_bonus = stats.power + hit_bonus + power_modifier(hunger_clock.get(entity));
 
-                    if damage == 0 {
-                        log.entries.push_back(format!("{} is unable to hurt {}", &name.name, &target_name.name));
-                    } else {
-                        log.entries.push_back(format!("{} hits {}, for {} hp.", &name.name, &target_name.name, damage));
-                        SufferDamage::new_damage(&mut suffer_damage, wants_melee.target, damage);
+                    let outcome =
+                        resolve_attack(&mut rng, attack_bonus, damage_dice, armour_class(target_stats.defense));
+                    // Log what will actually land once the target's resistances are applied
+                    let target_resistances = resistances.get(wants_melee.target);
+                    let landed = |damage| resisted_damage(target_resistances, damage, damage_type);
+                    let attacker = || LogLine::new().colored(&name.name, NAME_COLOR);
+                    match outcome {
+                        AttackOutcome::Fumble => {
+                            let line = attacker().text(" fumbles an attack on ").colored(&target_name.name, NAME_COLOR);
+                            log.add_line(LogCategory::Combat, line.text("!"));
+                        }
+                        AttackOutcome::Miss => {
+                            let line = attacker().text(" misses ").colored(&target_name.name, NAME_COLOR);
+                            log.add_line(LogCategory::Combat, line.text("."));
+                        }
+                        AttackOutcome::Hit(damage) | AttackOutcome::Critical(damage) if landed(damage) == 0 => {
+                            let line = attacker().text(" is unable to hurt ").colored(&target_name.name, NAME_COLOR);
+                            log.add_line(LogCategory::Combat, line.text("."));
+                        }
+                        AttackOutcome::Hit(damage) => {
+                            let line = attacker()
+                                .text(" hits ")
+                                .colored(&target_name.name, NAME_COLOR)
+                                .text(format!(" with {}, for ", attack_name))
+                                .colored(format!("{} {}", landed(damage), damage_type), AMOUNT_COLOR)
+                                .text(format!(" damage{}.", resistance_note(damage, landed(damage))));
+                            log.add_line(LogCategory::Combat, line);
+                            SufferDamage::new_damage(
+                                &mut suffer_damage,
+                                wants_melee.target,
+                                damage,
+                                damage_type,
+                                Some(entity),
+                            );
+                        }
+                        AttackOutcome::Critical(damage) => {
+                            let line = attacker()
+                                .text(" lands a critical hit on ")
+                                .colored(&target_name.name, NAME_COLOR)
+                                .text(format!(" with {}, for ", attack_name))
+                                .colored(format!("{} {}", landed(damage), damage_type), AMOUNT_COLOR)
+                                .text(format!(" damage{}!", resistance_note(damage, landed(damage))));
+                            log.add_line(LogCategory::Combat, line);
+                            SufferDamage::new_damage(
+                                &mut suffer_damage,
+                                wants_melee.target,
+                                damage,
+                                damage_type,
+                                Some(entity),
+                            );
+                        }
                     }
                 }
             }
@@ -35,3 +159,81 @@ impl<'a> System<'a> for MeleeCombatSystem {
         wants_melee.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rltk::{DiceType, RandomNumberGenerator};
+
+    use super::{armour_class, resolve_attack, AttackOutcome};
+
+    const ROLLS: usize = 2000;
+
+    fn outcomes(seed: u64, attack_bonus: i32, damage: DiceType, armour_class: i32) -> Vec<AttackOutcome> {
+        let mut rng = RandomNumberGenerator::seeded(seed);
+        (0..ROLLS).map(|_| resolve_attack(&mut rng, attack_bonus, damage, armour_class)).collect()
+    }
+
+    fn hits(results: &[AttackOutcome]) -> Vec<i32> {
+        results
+            .iter()
+            .filter_map(|o| match o {
+                AttackOutcome::Hit(dmg) => Some(*dmg),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn power_makes_hits_more_likely_but_not_harder() {
+        let damage = DiceType::new(1, 6, 2);
+        let weak = hits(&outcomes(42, 0, damage, armour_class(2)));
+        let strong = hits(&outcomes(42, 6, damage, armour_class(2)));
+        assert!(strong.len() > weak.len() * 3 / 2, "{} hits against {}", strong.len(), weak.len());
+        for dmg in weak.iter().chain(strong.iter()) {
+            assert!((3..=8).contains(dmg), "hit for {}", dmg);
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_outcomes() {
+        let damage = DiceType::new(1, 6, 2);
+        assert_eq!(outcomes(42, 5, damage, armour_class(2)), outcomes(42, 5, damage, armour_class(2)));
+    }
+
+    #[test]
+    fn damage_stays_within_dice_range() {
+        let damage = DiceType::new(1, 6, 2);
+        for outcome in outcomes(7, 5, damage, armour_class(1)) {
+            match outcome {
+                AttackOutcome::Hit(dmg) => assert!((3..=8).contains(&dmg), "hit for {}", dmg),
+                AttackOutcome::Critical(dmg) => assert!((4..=14).contains(&dmg), "crit for {}", dmg),
+                AttackOutcome::Miss | AttackOutcome::Fumble => {}
+            }
+        }
+    }
+
+    #[test]
+    fn unbeatable_armour_is_only_hit_by_criticals() {
+        let results = outcomes(1234, 0, DiceType::new(1, 4, 0), armour_class(100));
+        assert!(results
+            .iter()
+            .all(|o| matches!(o, AttackOutcome::Critical(_) | AttackOutcome::Miss | AttackOutcome::Fumble)));
+        assert!(results.iter().any(|o| matches!(o, AttackOutcome::Critical(_))));
+    }
+
+    #[test]
+    fn fumbles_miss_even_against_no_armour() {
+        let results = outcomes(99, 100, DiceType::new(1, 4, 0), armour_class(-10));
+        assert!(!results.contains(&AttackOutcome::Miss));
+        assert!(results.contains(&AttackOutcome::Fumble));
+    }
+
+    #[test]
+    fn negative_damage_is_clamped_to_zero() {
+        for outcome in outcomes(5, 100, DiceType::new(1, 4, -10), armour_class(0)) {
+            if let AttackOutcome::Hit(dmg) | AttackOutcome::Critical(dmg) = outcome {
+                assert_eq!(dmg, 0);
+            }
+        }
+    }
+}
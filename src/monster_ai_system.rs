@@ -1,8 +1,23 @@
 use rltk::Point;
 use specs::prelude::*;
 
-use crate::movement_util::can_move;
-use crate::{Map, Monster, MovementSpeed, Name, Position, Viewshed, WantsToMelee};
+use crate::components::{
+    EntityMoved, Immobilized, InBackpack, InflictsDamage, Item, ProvidesHealing, Ranged, WantsToPickupItem,
+};
+use crate::movement_util::{can_move, GameClock};
+use crate::{CombatStats, Map, Monster, MovementSpeed, Name, Position, Viewshed, WantsToMelee, WantsToUseItem};
+
+/// Monsters drink a healing potion once they drop below this fraction of their max HP
+const HEAL_THRESHOLD: f32 = 0.5;
+
+/// What a monster has decided to do this tick
+enum Action {
+    Heal(Entity),
+    Zap(Entity),
+    PickUp(Entity),
+    Attack,
+    Chase,
+}
 
 pub struct MonsterAI {}
 
@@ -18,12 +33,23 @@ impl<'a> System<'a> for MonsterAI {
         WriteStorage<'a, WantsToMelee>,
         ReadExpect<'a, Entity>,
         Entities<'a>,
+        ReadStorage<'a, CombatStats>,
+        ReadStorage<'a, InBackpack>,
+        ReadStorage<'a, Item>,
+        ReadStorage<'a, ProvidesHealing>,
+        ReadStorage<'a, Ranged>,
+        ReadStorage<'a, InflictsDamage>,
+        WriteStorage<'a, WantsToUseItem>,
+        WriteStorage<'a, WantsToPickupItem>,
+        WriteStorage<'a, EntityMoved>,
+        WriteStorage<'a, Immobilized>,
+        ReadExpect<'a, GameClock>,
     );
 
     fn run(
         &mut self,
         (
-            mut map,
+            map,
             mut viewshed,
             mut pos,
             monster,
@@ -33,37 +59,93 @@ impl<'a> System<'a> for MonsterAI {
             mut wants_to_melee,
             player_entity,
             entities,
+            combat_stats,
+            backpack,
+            items,
+            provides_healing,
+            ranged,
+            inflicts_damage,
+            mut wants_to_use,
+            mut wants_to_pickup,
+            mut moved,
+            mut immobilized,
+            clock,
         ): Self::SystemData,
     ) {
-        for (viewshed, pos, _monster, _name, movement_speed, entity) in
-            (&mut viewshed, &mut pos, &monster, &name, &mut movement_speed, &entities).join()
+        for (viewshed, pos, _monster, _name, movement_speed, entity, stats) in
+            (&mut viewshed, &mut pos, &monster, &name, &mut movement_speed, &entities, &combat_stats).join()
         {
-            if viewshed.visible_tiles.contains(&*player_pos) {
-                // Monster movement speed
-                // Also used to limit attack speed for now
-                if !can_move(movement_speed) {
-                    continue;
-                }
+            let can_see_player = viewshed.visible_tiles.contains(&*player_pos);
+            let distance = rltk::DistanceAlg::Pythagoras.distance2d(Point::new(pos.x, pos.y), *player_pos);
+            let carried = || (&entities, &backpack).join().filter(|(_, pack)| pack.owner == entity).map(|(e, _)| e);
 
+            let healing_potion = carried().find(|item| provides_healing.contains(*item));
+            let offensive_item = carried().find(|item| {
+                inflicts_damage.contains(*item) && ranged.get(*item).is_some_and(|r| distance <= r.range as f32)
+            });
+            // Only bother picking up things monsters know how to use
+            let item_underfoot = map.tile_content[map.xy_idx(pos.x, pos.y)]
+                .iter()
+                .copied()
+                .find(|e| items.contains(*e) && (provides_healing.contains(*e) || inflicts_damage.contains(*e)));
+
+            let low_hp = (stats.hp as f32) < stats.max_hp as f32 * HEAL_THRESHOLD;
+
+            let action = match (healing_potion, offensive_item, item_underfoot) {
+                (Some(potion), _, _) if low_hp => Action::Heal(potion),
                 // Stop moving if already next to player, then attack
-                let distance = rltk::DistanceAlg::Pythagoras.distance2d(Point::new(pos.x, pos.y), *player_pos);
-                if distance < 1.5 {
-                    // Attack goes here
+                _ if can_see_player && distance < 1.5 => Action::Attack,
+                (_, Some(item), _) if can_see_player => Action::Zap(item),
+                (_, _, Some(item)) => Action::PickUp(item),
+                _ if can_see_player => Action::Chase,
+                _ => continue,
+            };
+
+            // Monster movement speed
+            // Also used to limit attack and item use speed for now
+            if !can_move(movement_speed, &clock) {
+                continue;
+            }
+
+            match action {
+                Action::Heal(item) => {
+                    wants_to_use.insert(entity, WantsToUseItem { item, target: None }).expect("Could not use item");
+                }
+                Action::Zap(item) => {
+                    wants_to_use
+                        .insert(entity, WantsToUseItem { item, target: Some(*player_pos) })
+                        .expect("Could not use item");
+                }
+                Action::PickUp(item) => {
+                    wants_to_pickup
+                        .insert(entity, WantsToPickupItem { collected_by: entity, item })
+                        .expect("Could not pick up item");
+                }
+                Action::Attack => {
                     wants_to_melee
                         .insert(entity, WantsToMelee { target: *player_entity })
                         .expect("Could not add target");
-                    continue;
                 }
-
-                let path = rltk::a_star_search(
-                    map.xy_idx(pos.x, pos.y) as i32,
-                    map.xy_idx(player_pos.x, player_pos.y) as i32,
-                    &mut *map,
-                );
-                if path.success && path.steps.len() > 1 {
-                    pos.x = path.steps[1] as i32 % map.width;
-                    pos.y = path.steps[1] as i32 / map.width;
-                    viewshed.dirty = true;
+                Action::Chase => {
+                    // Stuck in a trap, so spend the turn struggling
+                    if let Some(stuck) = immobilized.get_mut(entity) {
+                        stuck.turns -= 1;
+                        if stuck.turns <= 0 {
+                            immobilized.remove(entity);
+                        }
+                        continue;
+                    }
+                    let path = rltk::a_star_search(
+                        map.xy_idx(pos.x, pos.y) as i32,
+                        map.xy_idx(player_pos.x, player_pos.y) as i32,
+                        &*map,
+                    );
+                    if path.success && path.steps.len() > 1 {
+                        pos.x = path.steps[1] as i32 % map.width;
+                        pos.y = path.steps[1] as i32 / map.width;
+                        viewshed.dirty = true;
+                        moved.insert(entity, EntityMoved {}).expect("Unable to mark movement");
+                    }
                 }
             }
         }
@@ -1,18 +1,26 @@
+use serde::{Deserialize, Serialize};
+
 use crate::MovementSpeed;
-use std::time::{Duration, SystemTime};
 
-// Ideally this should be based on tick time, not system time!
-pub fn can_move(movement_speed: &mut MovementSpeed) -> bool {
-    let current_time = SystemTime::now();
+/// Game time in milliseconds, only advancing while the game is running. Using it rather than the
+/// system clock keeps the simulation the same when a recorded run is replayed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GameClock {
+    pub elapsed_ms: u64,
+    /// Turns the player has taken
+    pub turn: u64,
+}
+
+pub fn can_move(movement_speed: &mut MovementSpeed, clock: &GameClock) -> bool {
     if let Some(last_move_time) = movement_speed.last_move_time {
-        let elapsed = current_time.duration_since(last_move_time).unwrap();
+        let elapsed = clock.elapsed_ms.saturating_sub(last_move_time);
 
         // Constrains speed of movement
-        if elapsed < Duration::from_millis(movement_speed.min_delay_ms) {
+        if elapsed < movement_speed.min_delay_ms + movement_speed.encumbrance_delay_ms {
             return false;
         }
     }
 
-    movement_speed.last_move_time = Some(current_time);
+    movement_speed.last_move_time = Some(clock.elapsed_ms);
     true
 }
@@ -1,27 +1,74 @@
-use std::cmp::{max, min};
 use std::ops::DerefMut;
 
-use rltk::{Point, VirtualKeyCode};
+use rltk::{Point, RandomNumberGenerator, Rltk};
 use specs::{Entity, Join, World, WorldExt};
 
-use crate::components::WantsToPickupItem;
+use crate::components::{
+    Container, EntityMoved, Experience, Hidden, Immobilized, Vendor, WantsToOpen, WantsToPickupItem, WantsToTrade,
+};
+use crate::command::Command;
+use crate::gamelog::LogCategory;
+use crate::gui::{InventoryMode, LevelUpSelection};
+use crate::identification::Identification;
+use crate::key_bindings::Action;
 use crate::map::Map;
-use crate::movement_util::can_move;
-use crate::{keys_util, CombatStats, GameLog, Item, MovementSpeed, Player, Position, State, Viewshed, WantsToMelee, RunState};
+use crate::movement_util::{can_move, GameClock};
+use crate::travel::{self, AutoMove};
+use crate::{
+    keys_util, CombatStats, GameLog, Item, MovementSpeed, Name, Player, Position, RunState, State, Viewshed,
+    WantsToMelee,
+};
 
 // Below cannot be in a system because they require context outside the ECS, such as Rltk
-pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) {
+/// Moves or attacks in a direction, returning true if the player spent a turn doing so
+pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) -> bool {
+    // Only a single step at a time, whatever a replay file says
+    if delta_x.abs() > 1 || delta_y.abs() > 1 {
+        return false;
+    }
     let mut positions = ecs.write_storage::<Position>();
     let mut players = ecs.write_storage::<Player>();
     let mut viewsheds = ecs.write_storage::<Viewshed>();
     let mut wants_to_melee = ecs.write_storage::<WantsToMelee>();
     let combat_stats = ecs.read_storage::<CombatStats>();
+    let vendors = ecs.read_storage::<Vendor>();
+    let mut wants_to_trade = ecs.write_storage::<WantsToTrade>();
+    let containers = ecs.read_storage::<Container>();
+    let mut wants_to_open = ecs.write_storage::<WantsToOpen>();
+    let mut immobilized = ecs.write_storage::<Immobilized>();
+    let mut moved = ecs.write_storage::<EntityMoved>();
     let entities = ecs.entities();
     let map = ecs.fetch::<Map>();
 
     for (entity, _player, pos, viewshed) in (&entities, &mut players, &mut positions, &mut viewsheds).join() {
         let destination_idx = map.xy_idx(pos.x + delta_x, pos.y + delta_y);
 
+        // Nothing gets past a corner, not even an attack
+        if map.cuts_corner(pos.x, pos.y, delta_x, delta_y) {
+            return false;
+        }
+
+        // Struggling against a trap takes the turn instead of moving
+        if let Some(stuck) = immobilized.get_mut(entity) {
+            stuck.turns -= 1;
+            if stuck.turns <= 0 {
+                immobilized.remove(entity);
+                ecs.fetch_mut::<GameLog>().add(LogCategory::Status, "You struggle free.");
+            }
+            return true;
+        }
+
+        // Bumping into a shopkeeper trades with them
+        if let Some(vendor) = map.tile_content[destination_idx].iter().find(|e| vendors.contains(**e)) {
+            wants_to_trade.insert(entity, WantsToTrade { vendor: *vendor }).expect("Add trade failed");
+            return false;
+        }
+        // and bumping into a container opens it
+        if let Some(container) = map.tile_content[destination_idx].iter().find(|e| containers.contains(**e)) {
+            wants_to_open.insert(entity, WantsToOpen { container: *container }).expect("Add open failed");
+            return false;
+        }
+
         // Targets
         for potential_target in map.tile_content[destination_idx].iter() {
             let target = combat_stats.get(*potential_target);
@@ -35,14 +82,15 @@ pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) {
                         },
                     )
                     .expect("Add target failed");
-                return; // So we don't move after attacking
+                return true; // So we don't move after attacking
             }
         }
 
         // Move
         if !map.blocked[destination_idx] {
-            pos.x = min(79, max(0, pos.x + delta_x));
-            pos.y = min(49, max(0, pos.y + delta_y));
+            moved.insert(entity, EntityMoved {}).expect("Unable to mark movement");
+            pos.x = (pos.x + delta_x).clamp(0, 79);
+            pos.y = (pos.y + delta_y).clamp(0, 49);
 
             viewshed.dirty = true;
 
@@ -50,49 +98,97 @@ pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) {
             let mut ppos = ecs.write_resource::<Point>();
             ppos.x = pos.x;
             ppos.y = pos.y;
+            return true;
         }
     }
+    false
 }
 
-pub fn player_input(gs: &mut State) -> RunState {
-    // Interaction
-    if keys_util::check_press(VirtualKeyCode::E, gs.client.keys.get_mut(&VirtualKeyCode::E)) {
-        get_item(&mut gs.ecs);
-    }
+pub fn player_input(gs: &mut State, ctx: &mut Rltk) -> RunState {
+    let mut took_turn = false;
 
     // Toggle/close inventory
-    if keys_util::check_press(VirtualKeyCode::I, gs.client.keys.get_mut(&VirtualKeyCode::I)) {
+    if gs.client.keys.check(Action::ToggleInventory) {
         gs.client.show_inventory = !gs.client.show_inventory;
+        gs.client.inventory.target = None;
+        gs.client.auto_move = None;
     }
-    if keys_util::check_press(VirtualKeyCode::Escape, gs.client.keys.get_mut(&VirtualKeyCode::Escape)) {
-        if !gs.client.show_inventory {
+    if gs.client.keys.check(Action::Cancel) {
+        // Stops any automatic walking first
+        if !gs.client.show_inventory && gs.client.auto_move.take().is_none() {
             return RunState::SaveGame;
         }
         gs.client.show_inventory = false;
-        gs.client.drop_inventory = false;
+        gs.client.inventory.reset_mode();
+    }
+    if gs.client.keys.check(Action::DropMode) && gs.client.show_inventory {
+        gs.client.inventory.mode.toggle(InventoryMode::Drop);
     }
-    if keys_util::check_press(VirtualKeyCode::G, gs.client.keys.get_mut(&VirtualKeyCode::G)) && gs.client.show_inventory {
-        gs.client.drop_inventory = !gs.client.drop_inventory;
+    if gs.client.keys.check(Action::ThrowMode) && gs.client.show_inventory {
+        gs.client.inventory.mode.toggle(InventoryMode::Throw);
     }
     
-    // Movement
-    player_input_free_movement(gs);
-    RunState::Running
+    // Everything else, unless the keys are busy navigating the inventory
+    if !gs.client.show_inventory {
+        if gs.client.keys.check(Action::PickUp) {
+            took_turn |= gs.perform(Command::PickUp);
+        }
+        if gs.client.keys.check(Action::Search) {
+            took_turn |= gs.perform(Command::Search);
+        }
+        if gs.client.keys.check(Action::AutoExplore) {
+            gs.client.auto_move = Some(AutoMove::Explore);
+        }
+        if gs.client.keys.check(Action::ShowLog) {
+            return RunState::LogViewer { offset: 0, filter: None };
+        }
+        if gs.client.keys.check(Action::Look) {
+            return RunState::LookMode { cursor: *gs.ecs.fetch::<Point>() };
+        }
+        if gs.client.keys.check(Action::CharacterSheet) {
+            return RunState::CharacterSheet;
+        }
+        if ctx.left_click {
+            took_turn |= click_tile(gs, ctx.mouse_point());
+        }
+        // Right-clicking a revealed tile opens a menu of what can be done with it
+        if keys_util::check_click(keys_util::RIGHT_MOUSE_BUTTON, &mut gs.client.right_button_held) {
+            let target = ctx.mouse_point();
+            let map = gs.ecs.fetch::<Map>();
+            if target.x < map.width && target.y < map.height && map.revealed_tiles[map.xy_idx(target.x, target.y)] {
+                gs.client.auto_move = None;
+                return RunState::ContextMenu { target, selection: 0 };
+            }
+        }
+        took_turn |= player_input_free_movement(gs);
+    }
+    if took_turn {
+        RunState::PlayerTurn
+    } else {
+        RunState::Running
+    }
 }
 
-fn player_input_free_movement(gs: &mut State) {
+/// Movement actions and the step each takes
+pub const DIRECTIONS: [(Action, i32, i32); 8] = [
+    (Action::MoveNorth, 0, -1),
+    (Action::MoveSouth, 0, 1),
+    (Action::MoveWest, -1, 0),
+    (Action::MoveEast, 1, 0),
+    (Action::MoveNorthWest, -1, -1),
+    (Action::MoveNorthEast, 1, -1),
+    (Action::MoveSouthWest, -1, 1),
+    (Action::MoveSouthEast, 1, 1),
+];
+
+fn player_input_free_movement(gs: &mut State) -> bool {
     //let mut key = ctx.key;
     //let mut client = &mut gs.client;
 
     // For constraining speed of free movement
     //let player = gs.ecs.read_storage::<Player>().fetched_entities().join().next().unwrap();  // Assumes 1 player
-    {
-        let player = gs.ecs.write_resource::<Entity>();
-
-        // Doing it all in one line beats the borrow checker here
-        if !can_move(gs.ecs.write_storage::<MovementSpeed>().get_mut(*player).unwrap().deref_mut()) {
-            return ();
-        }
+    if !player_can_move(&mut gs.ecs) {
+        return false;
     }
     // let current_time = SystemTime::now();
     // if let Some(last_key_time) = client.last_key_time {
@@ -124,29 +220,66 @@ fn player_input_free_movement(gs: &mut State) {
     //client.last_key_time = Some(current_time);
 
     // Free movement
-    let input = rltk::INPUT.lock();
+    let keys = &mut gs.client.keys;
+    if keys.check(Action::Wait) {
+        gs.client.auto_move = None;
+        return gs.perform(Command::Wait);
+    }
     let mut delta_x = 0;
     let mut delta_y = 0;
-    if input.is_key_pressed(VirtualKeyCode::Left) {
-        delta_x -= 1;
-    }
-    if input.is_key_pressed(VirtualKeyCode::Right) {
-        delta_x += 1;
+    for (action, x, y) in DIRECTIONS {
+        if keys.check(action) {
+            delta_x += x;
+            delta_y += y;
+        }
     }
-    if input.is_key_pressed(VirtualKeyCode::Up) {
-        delta_y -= 1;
+
+    // Holding two cardinal keys together moves diagonally in a single step
+    let (delta_x, delta_y) = (delta_x.signum(), delta_y.signum());
+    if delta_x == 0 && delta_y == 0 {
+        return auto_move_step(gs);
     }
-    if input.is_key_pressed(VirtualKeyCode::Down) {
-        delta_y += 1;
+    gs.client.auto_move = None;
+    gs.perform(Command::Move { delta_x, delta_y })
+}
+
+/// Limits how often the player can move, attack or wait
+fn player_can_move(ecs: &mut World) -> bool {
+    let player = ecs.write_resource::<Entity>();
+
+    // Doing it all in one line beats the borrow checker here
+    can_move(ecs.write_storage::<MovementSpeed>().get_mut(*player).unwrap().deref_mut(), &ecs.fetch::<GameClock>())
+}
+
+/// Left-clicking next to the player steps or attacks there straight away, while further tiles are
+/// travelled to
+fn click_tile(gs: &mut State, target: Point) -> bool {
+    let player_pos = *gs.ecs.fetch::<Point>();
+    let (delta_x, delta_y) = (target.x - player_pos.x, target.y - player_pos.y);
+    gs.client.auto_move = None;
+
+    if delta_x.abs() > 1 || delta_y.abs() > 1 {
+        gs.client.auto_move = travel::plan_travel(&gs.ecs, target);
+        return false;
     }
+    (delta_x != 0 || delta_y != 0) && player_can_move(&mut gs.ecs) && gs.perform(Command::Move { delta_x, delta_y })
+}
 
-    // Allow the player to move in y and x axes independently of each other
-    if delta_x != 0 {
-        try_move_player(delta_x, 0, &mut gs.ecs);
+/// Takes the next step of auto-explore or travel, if the player is doing either
+fn auto_move_step(gs: &mut State) -> bool {
+    let Some(mut auto_move) = gs.client.auto_move.take() else {
+        return false;
+    };
+    let Some(step) = travel::next_step(&gs.ecs, &mut auto_move) else {
+        return false;
+    };
+    if !gs.perform(Command::Move { delta_x: step.x, delta_y: step.y }) {
+        return false;
     }
-    if delta_y != 0 {
-        try_move_player(0, delta_y, &mut gs.ecs);
+    if !travel::found_item(&gs.ecs) {
+        gs.client.auto_move = Some(auto_move);
     }
+    true
 }
 
 // pub fn player_input(gs: &mut State, ctx: &mut Rltk) -> RunState {
@@ -176,7 +309,63 @@ fn player_input_free_movement(gs: &mut State) {
 //     RunState::Running
 // }
 
-fn get_item(ecs: &mut World) {
+/// Logs what the player can see on a tile
+pub fn examine(ecs: &World, target: Point) {
+    let map = ecs.fetch::<Map>();
+    let names = ecs.read_storage::<Name>();
+    let positions = ecs.read_storage::<Position>();
+    let hidden = ecs.read_storage::<Hidden>();
+    let identification = ecs.fetch::<Identification>();
+    let mut log = ecs.fetch_mut::<GameLog>();
+
+    if !map.visible_tiles[map.xy_idx(target.x, target.y)] {
+        log.add(LogCategory::System, "You can't see that from here.");
+        return;
+    }
+    let seen: Vec<String> = (&names, &positions, !&hidden)
+        .join()
+        .filter(|(_, pos, _)| pos.x == target.x && pos.y == target.y)
+        .map(|(name, _, _)| format!("a {}", identification.name_of(&name.name)))
+        .collect();
+    if seen.is_empty() {
+        log.add(LogCategory::System, "There is nothing of note there.");
+    } else {
+        log.add(LogCategory::System, format!("You see {}.", seen.join(", ")));
+    }
+}
+
+/// Searching has to roll at least this on a d20 to spot each hidden thing
+const SEARCH_DIFFICULTY: i32 = 10;
+
+/// Rolls to reveal hidden things next to the player. Always takes a turn.
+pub fn search(ecs: &mut World) -> bool {
+    let player_pos = *ecs.fetch::<Point>();
+    let entities = ecs.entities();
+    let positions = ecs.read_storage::<Position>();
+    let names = ecs.read_storage::<Name>();
+    let mut hidden = ecs.write_storage::<Hidden>();
+    let mut rng = ecs.write_resource::<RandomNumberGenerator>();
+    let mut log = ecs.fetch_mut::<GameLog>();
+
+    log.add(LogCategory::System, "You search the area.");
+    let nearby: Vec<Entity> = (&entities, &positions, &hidden)
+        .join()
+        .filter(|(_, pos, _)| i32::abs(pos.x - player_pos.x) <= 1 && i32::abs(pos.y - player_pos.y) <= 1)
+        .map(|(entity, _, _)| entity)
+        .collect();
+    for entity in nearby {
+        if rng.roll_dice(1, 20) >= SEARCH_DIFFICULTY {
+            hidden.remove(entity);
+            if let Some(name) = names.get(entity) {
+                log.add(LogCategory::System, format!("You spot a {}!", name.name));
+            }
+        }
+    }
+    true
+}
+
+/// Queues picking up the item under the player, returning true if there was one
+pub fn get_item(ecs: &mut World) -> bool {
     let player_pos = ecs.fetch::<Point>();
     let positions = ecs.read_storage::<Position>();
     let items = ecs.read_storage::<Item>();
@@ -194,7 +383,8 @@ fn get_item(ecs: &mut World) {
 
     match target_item {
         None => {
-            log.entries.push_back("There is nothing here to pick up.".to_string());
+            log.add(LogCategory::Item, "There is nothing here to pick up.");
+            false
         }
         Some(item) => {
             let mut pickup = ecs.write_storage::<WantsToPickupItem>();
@@ -207,6 +397,159 @@ fn get_item(ecs: &mut World) {
                     },
                 )
                 .expect("Unable to insert want to pickup");
+            true
+        }
+    }
+}
+
+/// Max HP gained automatically on every level up, on top of the chosen bonus
+const LEVEL_UP_HP: i32 = 5;
+
+/// Awards experience to the player, levelling them up as many times as it allows. Returns the
+/// number of levels gained, each of which earns a bonus from the level up screen.
+pub fn gain_experience(ecs: &mut World, amount: i32) -> i32 {
+    let player_entity = ecs.fetch::<Entity>();
+    let mut experience = ecs.write_storage::<Experience>();
+    let mut combat_stats = ecs.write_storage::<CombatStats>();
+    let mut log = ecs.fetch_mut::<GameLog>();
+
+    let (Some(exp), Some(stats)) = (experience.get_mut(*player_entity), combat_stats.get_mut(*player_entity)) else {
+        return 0;
+    };
+
+    exp.xp += amount;
+    let mut levels = 0;
+    while exp.xp >= exp.xp_to_next_level() {
+        exp.xp -= exp.xp_to_next_level();
+        exp.level += 1;
+        stats.max_hp += LEVEL_UP_HP;
+        stats.hp = stats.max_hp;
+        stats.power += 1;
+        log.add(LogCategory::Status, format!("Welcome to level {}! You feel stronger.", exp.level));
+        levels += 1;
+    }
+    levels
+}
+
+/// Applies the bonus the player picked on the level up screen
+pub fn apply_level_up_bonus(ecs: &mut World, selection: LevelUpSelection) {
+    let player_entity = ecs.fetch::<Entity>();
+    let mut combat_stats = ecs.write_storage::<CombatStats>();
+    let mut log = ecs.fetch_mut::<GameLog>();
+
+    if let Some(stats) = combat_stats.get_mut(*player_entity) {
+        match selection {
+            LevelUpSelection::Vitality => {
+                stats.max_hp += 10;
+                stats.hp += 10;
+                log.add(LogCategory::Status, "You feel more robust.");
+            }
+            LevelUpSelection::Might => {
+                stats.power += 1;
+                log.add(LogCategory::Status, "You feel mightier.");
+            }
+            LevelUpSelection::Toughness => {
+                stats.defense += 1;
+                log.add(LogCategory::Status, "You feel tougher.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::Builder;
+
+    use super::*;
+    use crate::map::{TileType, MAP_COUNT, MAP_HEIGHT, MAP_WIDTH};
+
+    /// The player standing at (5, 5) in an otherwise empty world
+    fn test_world() -> World {
+        let mut ecs = World::new();
+        ecs.register::<Position>();
+        ecs.register::<Name>();
+        ecs.register::<Hidden>();
+        ecs.insert(Point::new(5, 5));
+        ecs.insert(RandomNumberGenerator::seeded(1));
+        ecs.insert(GameLog::new(10));
+        ecs
+    }
+
+    /// A room with floor from (1, 1) to (5, 5) and a pillar at (3, 3), with the player at `start`
+    fn room_with_pillar(start: Point) -> World {
+        let mut map = Map {
+            tiles: vec![TileType::Wall; MAP_COUNT],
+            rooms: Vec::new(),
+            width: MAP_WIDTH as i32,
+            height: MAP_HEIGHT as i32,
+            revealed_tiles: vec![false; MAP_COUNT],
+            visible_tiles: vec![false; MAP_COUNT],
+            blocked: vec![false; MAP_COUNT],
+            tile_content: vec![Vec::new(); MAP_COUNT],
+        };
+        for x in 1..=5 {
+            for y in 1..=5 {
+                let idx = map.xy_idx(x, y);
+                map.tiles[idx] = if (x, y) == (3, 3) { TileType::Wall } else { TileType::Floor };
+            }
+        }
+        map.populate_blocked();
+
+        let mut ecs = World::new();
+        ecs.register::<Position>();
+        ecs.register::<Player>();
+        ecs.register::<Viewshed>();
+        ecs.register::<WantsToMelee>();
+        ecs.register::<CombatStats>();
+        ecs.register::<Vendor>();
+        ecs.register::<WantsToTrade>();
+        ecs.register::<Container>();
+        ecs.register::<WantsToOpen>();
+        ecs.register::<Immobilized>();
+        ecs.register::<EntityMoved>();
+        let player = ecs
+            .create_entity()
+            .with(Position { x: start.x, y: start.y })
+            .with(Player {})
+            .with(Viewshed { visible_tiles: Vec::new(), range: 8, dirty: false })
+            .build();
+        ecs.insert(player);
+        ecs.insert(map);
+        ecs.insert(start);
+        ecs.insert(GameLog::new(10));
+        ecs
+    }
+
+    #[test]
+    fn monsters_across_a_corner_cannot_be_attacked() {
+        let mut ecs = room_with_pillar(Point::new(2, 3));
+        let stats = CombatStats { max_hp: 10, hp: 10, defense: 0, power: 1 };
+        let monster = ecs.create_entity().with(Position { x: 3, y: 2 }).with(stats).build();
+        let idx = ecs.fetch::<Map>().xy_idx(3, 2);
+        ecs.fetch_mut::<Map>().tile_content[idx].push(monster);
+
+        assert!(!try_move_player(1, -1, &mut ecs));
+        let player = *ecs.fetch::<Entity>();
+        assert!(!ecs.read_storage::<WantsToMelee>().contains(player));
+        // Going round the pillar's corner is fine though
+        assert!(try_move_player(0, -1, &mut ecs));
+    }
+
+    fn hidden_trap(ecs: &mut World, x: i32, y: i32) -> Entity {
+        ecs.create_entity().with(Position { x, y }).with(Name { name: "Test Trap".to_string() }).with(Hidden {}).build()
+    }
+
+    #[test]
+    fn searching_only_finds_things_next_to_the_player() {
+        let mut ecs = test_world();
+        let near = hidden_trap(&mut ecs, 6, 6);
+        let far = hidden_trap(&mut ecs, 7, 5);
+
+        for _ in 0..50 {
+            assert!(search(&mut ecs));
         }
+        let hidden = ecs.read_storage::<Hidden>();
+        assert!(!hidden.contains(near));
+        assert!(hidden.contains(far));
     }
 }
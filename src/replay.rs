@@ -0,0 +1,303 @@
+//! Recording runs and playing them back, to reproduce bugs in combat and AI.
+//!
+//! A recording is the run's seed plus every command and tick in order. Since the world is generated
+//! from the seed and the simulation only moves on with ticks, feeding the same commands back in
+//! gives the same game, which the checksum saved at the end of the recording confirms.
+
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+
+use crate::command::Command;
+use crate::map::Map;
+use crate::movement_util::GameClock;
+use crate::{CombatStats, Experience, Gold, HungerClock, Immobilized, InBackpack, Position, Telepathy};
+
+/// One thing that happened in a recorded run
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Event {
+    /// A command, with the number of player turns taken before it
+    Command { turn: u64, command: Command },
+    /// The simulation ran a tick lasting this long
+    Tick { frame_ms: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Replay {
+    pub seed: u64,
+    pub events: Vec<Event>,
+    /// Checksum of the game when the recording stopped
+    pub checksum: u64,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> io::Result<Replay> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(io::BufReader::new(file))?)
+    }
+}
+
+/// Why a recording couldn't be written, or how a replay went differently from its recording
+#[derive(Debug)]
+pub enum ReplayError {
+    Write { path: String, error: serde_json::Error },
+    /// The command at this position in the recording was given on a different turn
+    TurnMismatch { event: usize, recorded: u64, actual: u64 },
+    Diverged { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Write { path, error } => write!(f, "Could not write replay to {}: {}", path, error),
+            ReplayError::TurnMismatch { event, recorded, actual } => write!(
+                f,
+                "Replay diverged! Event {} was recorded on turn {} but played on turn {}.",
+                event, recorded, actual
+            ),
+            ReplayError::Diverged { expected, actual } => {
+                write!(f, "Replay diverged! Expected checksum {:016x} but got {:016x}.", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Whether the current run is being recorded or played back
+#[derive(Default)]
+pub enum ReplayMode {
+    #[default]
+    Off,
+    Recording(Recorder),
+    Playing(Playback),
+}
+
+pub struct Recorder {
+    path: String,
+    replay: Replay,
+    turn: u64,
+}
+
+impl Recorder {
+    pub fn new(path: &str, seed: u64) -> Self {
+        Recorder { path: path.to_string(), replay: Replay { seed, events: Vec::new(), checksum: 0 }, turn: 0 }
+    }
+
+    pub fn command(&mut self, command: &Command) {
+        self.replay.events.push(Event::Command { turn: self.turn, command: command.clone() });
+    }
+
+    pub fn tick(&mut self, frame_ms: u64, took_turn: bool) {
+        self.replay.events.push(Event::Tick { frame_ms });
+        if took_turn {
+            self.turn += 1;
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Writes the recording out along with a checksum of how the game ended up, returning the number
+    /// of turns recorded
+    pub fn finish(&mut self, ecs: &World) -> Result<u64, ReplayError> {
+        self.replay.checksum = checksum(ecs);
+        File::create(&self.path)
+            .map_err(serde_json::Error::io)
+            .and_then(|file| serde_json::to_writer(io::BufWriter::new(file), &self.replay))
+            .map_err(|error| ReplayError::Write { path: self.path.clone(), error })?;
+        Ok(self.turn)
+    }
+}
+
+pub struct Playback {
+    replay: Replay,
+    next: usize,
+    /// First command played on a different turn than it was recorded on
+    turn_mismatch: Option<ReplayError>,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Self {
+        Playback { replay, next: 0, turn_mismatch: None }
+    }
+
+    pub fn next_event(&mut self) -> Option<Event> {
+        let event = self.replay.events.get(self.next).cloned();
+        self.next += 1;
+        event
+    }
+
+    /// Notes the first command that comes on a different turn than when it was recorded, which is
+    /// where the replay started to go its own way
+    pub fn check_turn(&mut self, recorded: u64, actual: u64) {
+        if recorded != actual && self.turn_mismatch.is_none() {
+            self.turn_mismatch = Some(ReplayError::TurnMismatch { event: self.next - 1, recorded, actual });
+        }
+    }
+
+    /// Compares the game after the replay with how the recording ended
+    pub fn verify(self, ecs: &World) -> Result<(), ReplayError> {
+        if let Some(mismatch) = self.turn_mismatch {
+            return Err(mismatch);
+        }
+        let actual = checksum(ecs);
+        if actual == self.replay.checksum {
+            Ok(())
+        } else {
+            Err(ReplayError::Diverged { expected: self.replay.checksum, actual })
+        }
+    }
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its algorithm is fixed, so checksums saved in recordings
+/// stay valid across Rust releases.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// Hash of where everything is, how healthy and hungry it is, who owns what, what the player has
+/// explored and how long the run has gone on, which any divergence in a replay soon shows up in
+pub fn checksum(ecs: &World) -> u64 {
+    let entities = ecs.entities();
+    let positions = ecs.read_storage::<Position>();
+    let combat_stats = ecs.read_storage::<CombatStats>();
+    let gold = ecs.read_storage::<Gold>();
+    let backpack = ecs.read_storage::<InBackpack>();
+    let hunger = ecs.read_storage::<HungerClock>();
+    let experience = ecs.read_storage::<Experience>();
+    let telepathy = ecs.read_storage::<Telepathy>();
+    let immobilized = ecs.read_storage::<Immobilized>();
+    let map = ecs.fetch::<Map>();
+    let clock = ecs.fetch::<GameClock>();
+
+    let mut hasher = FnvHasher::default();
+    for (entity, pos) in (&entities, &positions).join() {
+        (entity.id(), pos.x, pos.y).hash(&mut hasher);
+    }
+    for (entity, stats) in (&entities, &combat_stats).join() {
+        (entity.id(), stats.hp, stats.max_hp, stats.power, stats.defense).hash(&mut hasher);
+    }
+    for (entity, gold) in (&entities, &gold).join() {
+        (entity.id(), gold.amount).hash(&mut hasher);
+    }
+    for (entity, pack) in (&entities, &backpack).join() {
+        (entity.id(), pack.owner.id()).hash(&mut hasher);
+    }
+    for (entity, clock) in (&entities, &hunger).join() {
+        (entity.id(), clock.state as u8, clock.duration).hash(&mut hasher);
+    }
+    for (entity, experience) in (&entities, &experience).join() {
+        (entity.id(), experience.level, experience.xp).hash(&mut hasher);
+    }
+    for (entity, telepathy) in (&entities, &telepathy).join() {
+        (entity.id(), telepathy.turns).hash(&mut hasher);
+    }
+    for (entity, immobilized) in (&entities, &immobilized).join() {
+        (entity.id(), immobilized.turns).hash(&mut hasher);
+    }
+    map.revealed_tiles.hash(&mut hasher);
+    (clock.elapsed_ms, clock.turn).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{new_world, Client, State};
+
+    const SEED: u64 = 7;
+
+    fn new_state(seed: u64, replay: ReplayMode) -> State {
+        State { ecs: new_world(seed), client: Client::default(), replay }
+    }
+
+    /// Wanders around for a while, recording it
+    fn record_run() -> Replay {
+        let mut live = new_state(SEED, ReplayMode::Recording(Recorder::new("unused.json", SEED)));
+        let moves = [(1, 0), (0, 1), (-1, 0), (0, -1), (1, 1), (-1, -1)];
+        for (tick, (delta_x, delta_y)) in moves.iter().cycle().take(300).enumerate() {
+            let took_turn = tick % 3 == 0 && live.perform(Command::Move { delta_x: *delta_x, delta_y: *delta_y });
+            live.step(16, took_turn);
+        }
+        let ReplayMode::Recording(mut recorder) = std::mem::take(&mut live.replay) else {
+            unreachable!();
+        };
+        recorder.replay.checksum = checksum(&live.ecs);
+        recorder.replay
+    }
+
+    #[test]
+    fn checksum_hasher_is_fnv_1a() {
+        let mut hasher = FnvHasher::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn replay_reproduces_recorded_run() {
+        let mut replayed = new_state(SEED, ReplayMode::Playing(Playback::new(record_run())));
+        while replayed.replay_tick() {}
+        assert!(replayed.finish_replay().is_ok());
+    }
+
+    #[test]
+    fn replay_detects_divergence() {
+        // Same commands in a different dungeon end up somewhere else
+        let mut replayed = new_state(SEED + 1, ReplayMode::Playing(Playback::new(record_run())));
+        while replayed.replay_tick() {}
+        assert!(replayed.finish_replay().is_err());
+    }
+
+    #[test]
+    fn replay_detects_commands_on_the_wrong_turn() {
+        let mut replay = record_run();
+        let recorded = replay.events.iter_mut().filter_map(|event| match event {
+            Event::Command { turn, .. } => Some(turn),
+            Event::Tick { .. } => None,
+        });
+        *recorded.last().unwrap() += 1;
+        let mut replayed = new_state(SEED, ReplayMode::Playing(Playback::new(replay)));
+        while replayed.replay_tick() {}
+        assert!(matches!(replayed.finish_replay(), Err(ReplayError::TurnMismatch { .. })));
+    }
+
+    #[test]
+    fn checksum_covers_hunger_exploration_and_time() {
+        let ecs = new_world(SEED);
+        let before = checksum(&ecs);
+        let player = *ecs.fetch::<Entity>();
+        ecs.write_storage::<HungerClock>().get_mut(player).unwrap().duration -= 1;
+        assert_ne!(checksum(&ecs), before);
+
+        let ecs = new_world(SEED);
+        ecs.fetch_mut::<Map>().revealed_tiles[0] ^= true;
+        assert_ne!(checksum(&ecs), before);
+
+        let ecs = new_world(SEED);
+        ecs.fetch_mut::<GameClock>().elapsed_ms += 16;
+        assert_ne!(checksum(&ecs), before);
+    }
+}
@@ -1,19 +1,77 @@
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use specs::{Builder, World, WorldExt};
-use specs::error::NoError;
-use specs::saveload::{MarkedBuilder, SimpleMarker};
+use rltk::Point;
+use specs::{Builder, Entity, Join, World, WorldExt};
+use std::convert::Infallible;
+use specs::saveload::{DeserializeComponents, MarkedBuilder, SerializeComponents, SimpleMarker, SimpleMarkerAllocator};
+use serde::{Deserialize, Serialize};
 
 use crate::{BlocksTile, CombatStats, Consumable, InBackpack, InflictsDamage, Item,
-            Monster, Name, Player, Position, ProvidesHealing, Ranged, Renderable, SerializeMe, SufferDamage,
-            Viewshed, WantsToDropItem, WantsToMelee, WantsToPickupItem,
+            Monster, MovementSpeed, Name, Player, Position, ProvidesHealing, Ranged, Renderable, SerializeMe,
+            SufferDamage, Viewshed, WantsToDropItem, WantsToMelee, WantsToPickupItem,
             WantsToUseItem};
-use crate::components::SerializationHelper;
+use crate::components::{
+    Experience, ExplodesOnDeath, HungerClock, LootTable, NaturalAttack, ProvidesFood, Resistances, SerializationHelper,
+    SplitsOnDeath, ThrowingWeapon, WantsToThrowItem, Gold, Vendor, WantsToTrade, Container, Locked, Key,
+    InContainer, WantsToOpen, Hidden, EntryTrigger, EntityMoved, Immobilizes, Immobilized, Teleports,
+    MagicMapper, GrantsTelepathy, Telepathy, Description,
+};
+use crate::gamelog::{GameLog, SAVED_LOG_ENTRIES};
+use crate::identification::Identification;
+use crate::movement_util::GameClock;
+
+pub const SAVE_PATH: &str = "./savegame.json";
+/// Where a save is written before replacing the old one, so a failed save can't leave half a file behind
+const SAVE_TEMP_PATH: &str = "./savegame.json.tmp";
+
+/// Summary written at the start of the save, so the main menu can show it without loading the game
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SaveInfo {
+    pub level: i32,
+    pub turn: u64,
+    /// Seconds since the Unix epoch
+    pub saved_at: u64,
+}
+
+impl SaveInfo {
+    /// Roughly how long ago the game was saved, like "5m ago"
+    pub fn age(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        match now.saturating_sub(self.saved_at) {
+            0..=59 => "just now".to_string(),
+            secs @ 60..=3599 => format!("{}m ago", secs / 60),
+            secs @ 3600..=86399 => format!("{}h ago", secs / 3600),
+            secs => format!("{}d ago", secs / 86400),
+        }
+    }
+}
+
+pub fn does_save_exist() -> bool {
+    Path::new(SAVE_PATH).exists()
+}
+
+/// The summary of the save, if there is one and it can be read. Only the start of the file is read.
+pub fn save_info() -> Option<SaveInfo> {
+    let file = File::open(SAVE_PATH).ok()?;
+    SaveInfo::deserialize(&mut serde_json::Deserializer::from_reader(BufReader::new(file))).ok()
+}
+
+fn summarize(ecs: &World) -> SaveInfo {
+    let player_entity = *ecs.fetch::<Entity>();
+    SaveInfo {
+        level: ecs.read_storage::<Experience>().get(player_entity).map_or(1, |exp| exp.level),
+        turn: ecs.fetch::<GameClock>().turn,
+        saved_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()),
+    }
+}
 
 macro_rules! serialize_individually {
     ($ecs:expr, $ser:expr, $data:expr, $( $type:ty),*) => {
         $(
-        SerializeComponents::<NoError, SimpleMarker<SerializeMe>>::serialize(
+        SerializeComponents::<Infallible, SimpleMarker<SerializeMe>>::serialize(
             &( $ecs.read_storage::<$type>(), ),
             &$data.0,
             &$data.1,
@@ -24,12 +82,40 @@ macro_rules! serialize_individually {
     };
 }
 
+macro_rules! deserialize_individually {
+    ($ecs:expr, $de:expr, $data:expr, $( $type:ty),*) => {
+        $(
+        DeserializeComponents::<Infallible, _>::deserialize(
+            &mut ( &mut $ecs.write_storage::<$type>(), ),
+            &$data.0,
+            &mut $data.1,
+            &mut $data.2,
+            &mut $de,
+        )?;
+        )*
+    };
+}
+
 pub fn save_game(ecs : &mut World) {
+    let mut writer = BufWriter::new(File::create(SAVE_TEMP_PATH).unwrap());
+    serialize_world(ecs, &mut writer);
+    writer.flush().unwrap();
+    drop(writer);
+    fs::rename(SAVE_TEMP_PATH, SAVE_PATH).unwrap();
+}
+
+fn serialize_world<W: Write>(ecs: &mut World, writer: W) {
+    let mut serializer = serde_json::Serializer::new(writer);
+    summarize(ecs).serialize(&mut serializer).unwrap();
+
     // Create helper
     let mapcopy = ecs.get_mut::<super::map::Map>().unwrap().clone();
+    let identification = ecs.get_mut::<Identification>().unwrap().clone();
+    let log = ecs.fetch::<GameLog>().recent(SAVED_LOG_ENTRIES);
+    let clock = GameClock::clone(&ecs.fetch::<GameClock>());
     let savehelper = ecs
         .create_entity()
-        .with(SerializationHelper{ map : mapcopy })
+        .with(SerializationHelper{ map : mapcopy, identification, log, clock })
         .marked::<SimpleMarker<SerializeMe>>()
         .build();
 
@@ -37,15 +123,227 @@ pub fn save_game(ecs : &mut World) {
     {
         let data = ( ecs.entities(), ecs.read_storage::<SimpleMarker<SerializeMe>>() );
 
-        let writer = File::create("./savegame.json").unwrap();
-        let mut serializer = serde_json::Serializer::new(writer);
         serialize_individually!(ecs, serializer, data, Position, Renderable, Player, Viewshed, Monster, 
             Name, BlocksTile, CombatStats, SufferDamage, WantsToMelee, Item, Consumable, Ranged, InflictsDamage, 
             ProvidesHealing, InBackpack, WantsToPickupItem, WantsToUseItem,
-            WantsToDropItem, SerializationHelper
+            WantsToDropItem, HungerClock, ProvidesFood, Experience, NaturalAttack, Resistances, LootTable,
+            SplitsOnDeath, ExplodesOnDeath, WantsToThrowItem, ThrowingWeapon, Gold, Vendor, WantsToTrade,
+            Container, Locked, Key, InContainer, WantsToOpen, Hidden, EntryTrigger, EntityMoved, Immobilizes,
+            Immobilized, Teleports, MagicMapper, GrantsTelepathy, Telepathy, MovementSpeed, Description,
+            SerializationHelper
         );
     }
 
     // Clean up
     ecs.delete_entity(savehelper).expect("Crash on cleanup");
-}
\ No newline at end of file
+}
+
+/// Replaces everything in the world with the saved game. On failure the world is left half loaded,
+/// so it should be a fresh one that can be thrown away.
+pub fn load_game(ecs: &mut World) -> serde_json::Result<()> {
+    let data = fs::read_to_string(SAVE_PATH).map_err(serde_json::Error::io)?;
+    deserialize_world(ecs, &data)
+}
+
+fn deserialize_world(ecs: &mut World, data: &str) -> serde_json::Result<()> {
+    // Delete everything
+    ecs.delete_all();
+
+    {
+        let mut de = serde_json::Deserializer::from_str(data);
+        SaveInfo::deserialize(&mut de)?;
+        let mut data = (
+            ecs.entities(),
+            ecs.write_storage::<SimpleMarker<SerializeMe>>(),
+            ecs.write_resource::<SimpleMarkerAllocator<SerializeMe>>(),
+        );
+        deserialize_individually!(ecs, de, data, Position, Renderable, Player, Viewshed, Monster,
+            Name, BlocksTile, CombatStats, SufferDamage, WantsToMelee, Item, Consumable, Ranged, InflictsDamage,
+            ProvidesHealing, InBackpack, WantsToPickupItem, WantsToUseItem,
+            WantsToDropItem, HungerClock, ProvidesFood, Experience, NaturalAttack, Resistances, LootTable,
+            SplitsOnDeath, ExplodesOnDeath, WantsToThrowItem, ThrowingWeapon, Gold, Vendor, WantsToTrade,
+            Container, Locked, Key, InContainer, WantsToOpen, Hidden, EntryTrigger, EntityMoved, Immobilizes,
+            Immobilized, Teleports, MagicMapper, GrantsTelepathy, Telepathy, MovementSpeed, Description,
+            SerializationHelper
+        );
+    }
+
+    // Put the resources kept in the helper back, then throw it away
+    let helper_entity = {
+        let entities = ecs.entities();
+        let helpers = ecs.read_storage::<SerializationHelper>();
+        let (helper_entity, helper) = (&entities, &helpers)
+            .join()
+            .next()
+            .ok_or_else(|| serde::de::Error::custom("save has no serialization helper"))?;
+
+        let mut map = ecs.write_resource::<super::map::Map>();
+        *map = helper.map.clone();
+        map.tile_content = vec![Vec::new(); map.tiles.len()];
+        *ecs.write_resource::<Identification>() = helper.identification.clone();
+        *ecs.write_resource::<GameClock>() = helper.clock.clone();
+        let mut log = ecs.write_resource::<GameLog>();
+        log.entries.clear();
+        log.entries.extend(helper.log.iter().cloned());
+        log.turn = helper.clock.turn;
+        helper_entity
+    };
+    ecs.delete_entity(helper_entity).expect("Unable to delete helper");
+
+    let player = {
+        let entities = ecs.entities();
+        let players = ecs.read_storage::<Player>();
+        let positions = ecs.read_storage::<Position>();
+        (&entities, &players, &positions).join().next().map(|(entity, _, pos)| (entity, Point::new(pos.x, pos.y)))
+    };
+    let (player_entity, player_pos) = player.ok_or_else(|| serde::de::Error::custom("save has no player"))?;
+    ecs.insert(player_entity);
+    ecs.insert(player_pos);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rltk::RandomNumberGenerator;
+
+    use super::*;
+    use crate::gamelog::{LogCategory, LOG_CAPACITY};
+    use crate::map::{Map, TileType};
+    use crate::register_components;
+    use crate::spawner::{self, HEALTH_POTION};
+
+    const SIZE: i32 = 10;
+
+    /// An open room with the player standing at (2, 2), and the unidentified item names shuffled by
+    /// `seed`
+    fn test_world(seed: u64) -> World {
+        let count = (SIZE * SIZE) as usize;
+        let map = Map {
+            tiles: vec![TileType::Floor; count],
+            rooms: Vec::new(),
+            width: SIZE,
+            height: SIZE,
+            revealed_tiles: vec![false; count],
+            visible_tiles: vec![false; count],
+            blocked: vec![false; count],
+            tile_content: vec![Vec::new(); count],
+        };
+
+        let mut world = World::new();
+        register_components(&mut world);
+        world.insert(Identification::new(&mut RandomNumberGenerator::seeded(seed)));
+        world.insert(GameClock::default());
+        world.insert(GameLog::new(LOG_CAPACITY));
+        world.insert(map);
+        let player = spawner::player(&mut world, 2, 2);
+        world.insert(player);
+        world.insert(Point::new(2, 2));
+        world
+    }
+
+    /// Saves one world and loads it into another
+    fn round_trip(saved: &mut World) -> World {
+        let mut data = Vec::new();
+        serialize_world(saved, &mut data);
+        let mut loaded = test_world(2);
+        deserialize_world(&mut loaded, std::str::from_utf8(&data).unwrap()).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn log_and_turn_survive_a_save() {
+        let mut saved = test_world(1);
+        saved.write_resource::<GameClock>().turn = 42;
+        saved.write_resource::<GameClock>().elapsed_ms = 9000;
+        saved.fetch_mut::<GameLog>().add(LogCategory::Combat, "The orc misses you.");
+        let loaded = round_trip(&mut saved);
+
+        assert_eq!(loaded.fetch::<GameClock>().turn, 42);
+        assert_eq!(loaded.fetch::<GameClock>().elapsed_ms, 9000);
+        let log = loaded.fetch::<GameLog>();
+        let texts: Vec<&str> = log.entries.iter().map(|entry| entry.segments[0].text.as_str()).collect();
+        assert_eq!(texts, ["The orc misses you."]);
+        assert_eq!(log.turn, 42);
+    }
+
+    #[test]
+    fn summary_is_read_from_the_start_of_the_save() {
+        let mut saved = test_world(1);
+        saved.write_resource::<GameClock>().turn = 42;
+        let mut data = Vec::new();
+        serialize_world(&mut saved, &mut data);
+
+        let info = SaveInfo::deserialize(&mut serde_json::Deserializer::from_reader(data.as_slice())).unwrap();
+        assert_eq!((info.level, info.turn), (1, 42));
+    }
+
+    #[test]
+    fn player_and_map_survive_a_save() {
+        let mut saved = test_world(1);
+        let player = *saved.fetch::<Entity>();
+        *saved.write_storage::<Position>().get_mut(player).unwrap() = Position { x: 5, y: 6 };
+        let wall = saved.fetch::<Map>().xy_idx(4, 4);
+        saved.fetch_mut::<Map>().tiles[wall] = TileType::Wall;
+        let loaded = round_trip(&mut saved);
+
+        assert_eq!(*loaded.fetch::<Point>(), Point::new(5, 6));
+        let player_entity = *loaded.fetch::<Entity>();
+        assert!(loaded.read_storage::<Player>().contains(player_entity));
+        assert!(loaded.read_storage::<MovementSpeed>().contains(player_entity));
+        let (saved_map, loaded_map) = (saved.fetch::<Map>(), loaded.fetch::<Map>());
+        assert!(saved_map.tiles == loaded_map.tiles);
+        assert_eq!(loaded_map.tile_content.len(), loaded_map.tiles.len());
+        // Nothing is left of the helper or the world it was loaded over
+        assert_eq!(loaded.read_storage::<SerializationHelper>().join().count(), 0);
+        assert_eq!(loaded.read_storage::<Name>().join().count(), saved.read_storage::<Name>().join().count());
+    }
+
+    #[test]
+    fn identification_survives_a_save() {
+        let mut saved = test_world(1);
+        saved.write_resource::<Identification>().identify(HEALTH_POTION);
+        let loaded = round_trip(&mut saved);
+
+        let (saved_id, loaded_id) = (saved.fetch::<Identification>(), loaded.fetch::<Identification>());
+        assert_eq!(loaded_id.obfuscated, saved_id.obfuscated);
+        assert!(loaded_id.is_identified(HEALTH_POTION));
+        assert_eq!(loaded_id.identified, saved_id.identified);
+    }
+
+    #[test]
+    fn chest_contents_survive_a_save() {
+        let mut saved = test_world(1);
+        let chest = saved
+            .create_entity()
+            .with(Name { name: "Test Chest".to_string() })
+            .with(Container {})
+            .with(Locked {})
+            .marked::<SimpleMarker<SerializeMe>>()
+            .build();
+        for name in ["Apple", "Bone"] {
+            saved
+                .create_entity()
+                .with(Name { name: name.to_string() })
+                .with(Item { weight: 1.0, value: 1 })
+                .with(InContainer { container: chest })
+                .marked::<SimpleMarker<SerializeMe>>()
+                .build();
+        }
+        let loaded = round_trip(&mut saved);
+
+        let entities = loaded.entities();
+        let names = loaded.read_storage::<Name>();
+        let (chest, _, _) = (&entities, &names, &loaded.read_storage::<Container>())
+            .join()
+            .find(|(_, name, _)| name.name == "Test Chest")
+            .expect("chest was not loaded");
+        assert!(loaded.read_storage::<Locked>().contains(chest));
+        let mut contents: Vec<&str> = (&names, &loaded.read_storage::<InContainer>())
+            .join()
+            .filter(|(_, inside)| inside.container == chest)
+            .map(|(name, _)| name.name.as_str())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, ["Apple", "Bone"]);
+    }
+}
@@ -0,0 +1,115 @@
+//! Options the player can change from the main menu, saved to `settings.json` and applied at startup.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+
+use rltk::{BTerm, RltkBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::gamelog::LOG_CAPACITY;
+
+pub const SETTINGS_PATH: &str = "./settings.json";
+
+/// Bounds and step for the key repeat delay option
+pub const KEY_REPEAT_DELAY_RANGE_MS: (u64, u64) = (100, 1000);
+pub const KEY_REPEAT_DELAY_STEP_MS: u64 = 100;
+/// Bounds and step for the message log length option
+pub const LOG_LENGTH_RANGE: (usize, usize) = (100, 1000);
+pub const LOG_LENGTH_STEP: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Font {
+    Terminal8x8,
+    Vga8x16,
+}
+
+impl fmt::Display for Font {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Font::Terminal8x8 => write!(f, "Terminal 8x8"),
+            Font::Vga8x16 => write!(f, "VGA 8x16"),
+        }
+    }
+}
+
+/// Settings missing from the file keep their defaults
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct Settings {
+    pub scanlines: bool,
+    /// Only changes when the game is restarted, since the window is built with it
+    pub font: Font,
+    /// How long a held key waits before repeating its action. Movement keys are limited by movement
+    /// speed instead and aren't affected.
+    pub key_repeat_delay_ms: u64,
+    /// How many messages the log keeps
+    pub log_length: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings { scanlines: true, font: Font::Terminal8x8, key_repeat_delay_ms: 500, log_length: LOG_CAPACITY }
+    }
+}
+
+impl Settings {
+    /// Loads settings from the file. A missing file just means defaults.
+    pub fn load(path: &str) -> io::Result<Settings> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Settings::default()),
+            Err(e) => return Err(e),
+        };
+        let mut settings: Settings = serde_json::from_str(&contents)?;
+        settings.clamp();
+        Ok(settings)
+    }
+
+    /// Brings values from a hand-edited file back within what the options screen allows
+    fn clamp(&mut self) {
+        let (min, max) = KEY_REPEAT_DELAY_RANGE_MS;
+        self.key_repeat_delay_ms = self.key_repeat_delay_ms.clamp(min, max);
+        let (min, max) = LOG_LENGTH_RANGE;
+        self.log_length = self.log_length.clamp(min, max);
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let file = File::create(path)?;
+        Ok(serde_json::to_writer_pretty(io::BufWriter::new(file), self)?)
+    }
+
+    /// Starts building the window in the chosen font
+    pub fn window(&self) -> RltkBuilder {
+        match self.font {
+            Font::Terminal8x8 => RltkBuilder::simple80x50(),
+            Font::Vga8x16 => RltkBuilder::vga80x50(),
+        }
+    }
+
+    pub fn apply_scanlines(&self, ctx: &mut BTerm) {
+        if self.scanlines {
+            ctx.with_post_scanlines(true);
+        } else {
+            ctx.post_scanlines = false;
+            ctx.post_screenburn = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let mut settings = Settings { key_repeat_delay_ms: 5, log_length: 1_000_000, ..Settings::default() };
+        settings.clamp();
+        assert_eq!(settings.key_repeat_delay_ms, KEY_REPEAT_DELAY_RANGE_MS.0);
+        assert_eq!(settings.log_length, LOG_LENGTH_RANGE.1);
+
+        let mut defaults = Settings::default();
+        defaults.clamp();
+        assert_eq!(defaults, Settings::default());
+    }
+}
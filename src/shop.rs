@@ -0,0 +1,63 @@
+use specs::{Entity, World, WorldExt};
+
+use crate::components::{Gold, InBackpack, Item};
+use crate::encumbrance_system::can_carry;
+use crate::gamelog::LogCategory;
+use crate::identification::display_name;
+use crate::{GameLog, Name};
+
+/// Shops buy things back for this fraction of what they sell them for
+const SELL_PRICE_RATIO: f32 = 0.5;
+
+/// What the player pays a shop for an item
+pub fn buy_price(item: &Item) -> i32 {
+    item.value
+}
+
+/// What a shop pays the player for an item
+pub fn sell_price(item: &Item) -> i32 {
+    (item.value as f32 * SELL_PRICE_RATIO) as i32
+}
+
+/// Moves an item from a vendor to the player if the player can afford and carry it
+pub fn buy(ecs: &mut World, vendor: Entity, item: Entity) {
+    let player_entity = *ecs.fetch::<Entity>();
+    let price = ecs.read_storage::<Item>().get(item).map_or(0, buy_price);
+    let message = if !can_carry(ecs, player_entity, item) {
+        format!("The {} is too heavy to carry.", display_name(ecs, item))
+    } else if transfer(ecs, item, vendor, player_entity, price) {
+        format!("You buy the {} for {} gold.", display_name(ecs, item), price)
+    } else {
+        format!("You can't afford the {}.", display_name(ecs, item))
+    };
+    ecs.fetch_mut::<GameLog>().add(LogCategory::Item, message);
+}
+
+/// Moves an item from the player to a vendor if the vendor can afford it
+pub fn sell(ecs: &mut World, vendor: Entity, item: Entity) {
+    let player_entity = *ecs.fetch::<Entity>();
+    let price = ecs.read_storage::<Item>().get(item).map_or(0, sell_price);
+    let message = if transfer(ecs, item, player_entity, vendor, price) {
+        format!("You sell the {} for {} gold.", display_name(ecs, item), price)
+    } else {
+        let vendor_name = ecs.read_storage::<Name>().get(vendor).unwrap().name.clone();
+        format!("{} can't afford the {}.", vendor_name, display_name(ecs, item))
+    };
+    ecs.fetch_mut::<GameLog>().add(LogCategory::Item, message);
+}
+
+/// Hands an item from seller to buyer in exchange for gold, returning false if the buyer can't pay
+fn transfer(ecs: &mut World, item: Entity, seller: Entity, buyer: Entity, price: i32) -> bool {
+    let mut wallets = ecs.write_storage::<Gold>();
+    if wallets.get(buyer).map_or(0, |wallet| wallet.amount) < price {
+        return false;
+    }
+    if let Some(wallet) = wallets.get_mut(buyer) {
+        wallet.amount -= price;
+    }
+    if let Some(wallet) = wallets.get_mut(seller) {
+        wallet.amount += price;
+    }
+    ecs.write_storage::<InBackpack>().insert(item, InBackpack { owner: buyer }).expect("Unable to hand over item");
+    true
+}
@@ -1,13 +1,36 @@
 use crate::map::MAP_WIDTH;
 use crate::rect::Rect;
 use crate::{BlocksTile, CombatStats, Consumable, Item, Monster, MovementSpeed, Name, Player, Position, ProvidesHealing, Renderable, SerializeMe, Viewshed};
-use rltk::{RandomNumberGenerator, RGB};
-use specs::{Builder, Entity, World, WorldExt};
+use rltk::{DiceType, RandomNumberGenerator, RGB};
+use specs::{Builder, Entity, EntityBuilder, World, WorldExt};
 use specs::saveload::{MarkedBuilder, SimpleMarker};
-use crate::components::{InflictsDamage, Ranged};
+use crate::components::{
+    Container, DamageType, Description, EntryTrigger, Experience, ExplodesOnDeath, Gold, Hidden, HungerClock,
+    HungerState, GrantsTelepathy, Immobilizes, InBackpack, InContainer, InflictsDamage, Key, Locked, LootDrop,
+    LootTable, MagicMapper, NaturalAttack, ProvidesFood, Ranged, Resistances, SplitsOnDeath, Teleports,
+    ThrowingWeapon, Vendor,
+};
+use crate::map::Map;
+use crate::hunger_system::WELL_FED_DURATION;
 
 const MAX_MONSTERS: i32 = 4;
 const MAX_ITEMS: i32 = 2;
+/// One in this many rooms has a chest
+const CHEST_CHANCE: i32 = 3;
+/// One in this many chests is locked
+const LOCKED_CHEST_CHANCE: i32 = 3;
+const MAX_CHEST_ITEMS: i32 = 3;
+const MAX_TRAPS: i32 = 2;
+/// Gold a shopkeeper has to buy things from the player with
+const SHOPKEEPER_GOLD: i32 = 300;
+
+pub const HEALTH_POTION: &str = "Health Potion";
+pub const MAGIC_MISSILE_SCROLL: &str = "Magic Missile Scroll";
+pub const MAGIC_MAPPING_SCROLL: &str = "Magic Mapping Scroll";
+pub const TELEPATHY_SCROLL: &str = "Telepathy Scroll";
+/// Magic items that start each run unidentified
+pub const POTIONS: &[&str] = &[HEALTH_POTION];
+pub const SCROLLS: &[&str] = &[MAGIC_MISSILE_SCROLL, MAGIC_MAPPING_SCROLL, TELEPATHY_SCROLL];
 
 /// Spawns the player and returns the entity object.
 pub fn player(ecs: &mut World, player_x: i32, player_y: i32) -> Entity {
@@ -34,6 +57,7 @@ pub fn player(ecs: &mut World, player_x: i32, player_y: i32) -> Entity {
         .with(MovementSpeed {
             min_delay_ms: 60,
             last_move_time: None,
+            encumbrance_delay_ms: 0,
         })
         .with(CombatStats {
             max_hp: 30,
@@ -41,6 +65,18 @@ pub fn player(ecs: &mut World, player_x: i32, player_y: i32) -> Entity {
             defense: 2,
             power: 5,
         })
+        .with(HungerClock {
+            state: HungerState::WellFed,
+            duration: WELL_FED_DURATION,
+        })
+        .with(Experience { level: 1, xp: 0 })
+        .with(Gold { amount: 0 })
+        .with(NaturalAttack {
+            name: "fists".to_string(),
+            hit_bonus: 0,
+            damage: DiceType::new(1, 6, 1),
+            damage_type: DamageType::Bludgeoning,
+        })
         .marked::<SimpleMarker<SerializeMe>>()
         .build()
 }
@@ -50,11 +86,13 @@ pub fn random_monster(ecs: &mut World, x: i32, y: i32) {
     let roll: i32;
     {
         let mut rng = ecs.write_resource::<RandomNumberGenerator>();
-        roll = rng.roll_dice(1, 2);
+        roll = rng.roll_dice(1, 6);
     }
     match roll {
-        1 => orc(ecs, x, y),
-        _ => goblin(ecs, x, y),
+        1 | 2 => orc(ecs, x, y),
+        3 | 4 => goblin(ecs, x, y),
+        5 => slime(ecs, x, y),
+        _ => bloat(ecs, x, y),
     }
 }
 
@@ -108,29 +146,220 @@ pub fn spawn_room(ecs: &mut World, room: &Rect) {
         let y = idx / MAP_WIDTH;
         random_item(ecs, x as i32, y as i32);
     }
+
+    // Some rooms get a chest, kept off the centre so it doesn't block corridors through the room
+    let chest_spot = {
+        let mut rng = ecs.write_resource::<RandomNumberGenerator>();
+        let x = room.x1 + rng.roll_dice(1, i32::abs(room.x2 - room.x1));
+        let y = room.y1 + rng.roll_dice(1, i32::abs(room.y2 - room.y1));
+        let idx = (y as usize * MAP_WIDTH) + x as usize;
+        let taken = (x, y) == room.center() || monster_spawn_points.contains(&idx) || item_spawn_points.contains(&idx);
+        (rng.roll_dice(1, CHEST_CHANCE) == 1 && !taken).then_some((x, y))
+    };
+    if let Some((x, y)) = chest_spot {
+        chest(ecs, x, y);
+    }
+
+    // Hidden traps, away from where monsters start so they don't set them off straight away
+    let mut trap_spots: Vec<(i32, i32)> = Vec::new();
+    {
+        let mut rng = ecs.write_resource::<RandomNumberGenerator>();
+        let num_traps = rng.roll_dice(1, MAX_TRAPS + 2) - 2;
+        for _i in 0..num_traps {
+            let x = room.x1 + rng.roll_dice(1, i32::abs(room.x2 - room.x1));
+            let y = room.y1 + rng.roll_dice(1, i32::abs(room.y2 - room.y1));
+            let idx = (y as usize * MAP_WIDTH) + x as usize;
+            if !monster_spawn_points.contains(&idx) && chest_spot != Some((x, y)) && !trap_spots.contains(&(x, y)) {
+                trap_spots.push((x, y));
+            }
+        }
+    }
+    for (x, y) in trap_spots {
+        random_trap(ecs, x, y);
+    }
+}
+
+fn random_trap(ecs: &mut World, x: i32, y: i32) -> Entity {
+    let roll = ecs.write_resource::<RandomNumberGenerator>().roll_dice(1, 3);
+    let builder = ecs
+        .create_entity()
+        .with(Position { x, y })
+        .with(Hidden {})
+        .with(EntryTrigger {});
+    let (name, description, builder) = match roll {
+        1 => (
+            "Bear Trap",
+            "Steel jaws that snap shut on whatever steps in them.",
+            builder
+                .with(InflictsDamage { damage: 4, damage_type: DamageType::Slashing })
+                .with(Immobilizes { turns: 4 }),
+        ),
+        2 => ("Teleport Trap", "A faint rune that flings whoever treads on it elsewhere.", builder.with(Teleports {})),
+        _ => (
+            "Dart Trap",
+            "A hidden launcher that fires a poisoned dart.",
+            builder.with(InflictsDamage { damage: 6, damage_type: DamageType::Poison }),
+        ),
+    };
+    builder
+        .with(Name { name: name.to_string() })
+        .with(Description { text: description.to_string() })
+        .with(Renderable {
+            glyph: rltk::to_cp437('^'),
+            fg: RGB::named(rltk::RED),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 2,
+        })
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build()
 }
 
 fn random_item(ecs: &mut World, x: i32, y: i32) -> Entity {
     let roll: i32;
     {
         let mut rng = ecs.write_resource::<RandomNumberGenerator>();
-        roll = rng.roll_dice(1, 2);
+        roll = rng.roll_dice(1, 8);
     }
     match roll {
         1 => { health_potion(ecs, x, y) }
+        2 => { rations(ecs, x, y) }
+        3 => { throwing_dagger(ecs, x, y) }
+        4 => { gold_pile(ecs, x, y) }
+        5 => { iron_key(ecs, x, y) }
+        6 => { magic_mapping_scroll(ecs, x, y) }
+        7 => { telepathy_scroll(ecs, x, y) }
         _ => { magic_missile_scroll(ecs, x, y) }
     }
 }
 
+/// Spawns whatever a loot table rolled at a given location
+pub fn loot_drop(ecs: &mut World, drop: LootDrop, x: i32, y: i32) -> Option<Entity> {
+    match drop {
+        LootDrop::Nothing => None,
+        LootDrop::HealthPotion => Some(health_potion(ecs, x, y)),
+        LootDrop::Rations => Some(rations(ecs, x, y)),
+        LootDrop::MagicMissileScroll => Some(magic_missile_scroll(ecs, x, y)),
+        LootDrop::Gold => Some(gold_pile(ecs, x, y)),
+    }
+}
+
+/// Spawns the two halves of a split slime on free tiles around where it died
+pub fn split_slime(ecs: &mut World, x: i32, y: i32) {
+    let mut spots: Vec<(i32, i32)> = Vec::new();
+    {
+        let map = ecs.fetch::<Map>();
+        for (dx, dy) in [(0, 0), (-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (1, -1), (-1, 1), (1, 1)] {
+            let (sx, sy) = (x + dx, y + dy);
+            if sx < 0 || sx >= map.width || sy < 0 || sy >= map.height {
+                continue;
+            }
+            // The victim's own tile is still marked as blocked by the dying slime itself, which is free to reuse
+            let idx = map.xy_idx(sx, sy);
+            if (dx == 0 && dy == 0) || !map.blocked[idx] {
+                spots.push((sx, sy));
+            }
+        }
+    }
+
+    for (sx, sy) in spots.into_iter().take(2) {
+        small_slime(ecs, sx, sy);
+    }
+}
+
+/// Drops for common monsters, which usually drop nothing
+fn common_loot() -> LootTable {
+    LootTable {
+        drops: vec![
+            (LootDrop::Nothing, 6),
+            (LootDrop::Gold, 3),
+            (LootDrop::HealthPotion, 2),
+            (LootDrop::Rations, 2),
+            (LootDrop::MagicMissileScroll, 1),
+        ],
+    }
+}
+
 fn orc(ecs: &mut World, x: i32, y: i32) {
-    monster(ecs, x, y, rltk::to_cp437('o'), "Orc");
+    monster(ecs, x, y, rltk::to_cp437('o'), "Orc")
+        .with(Description { text: "A hulking brute with a hide thick enough to turn aside a club.".to_string() })
+        .with(CombatStats { max_hp: 16, hp: 16, defense: 1, power: 4 })
+        .with(Experience { level: 1, xp: 0 })
+        .with(NaturalAttack {
+            name: "fists".to_string(),
+            hit_bonus: 0,
+            damage: DiceType::new(1, 6, 0),
+            damage_type: DamageType::Bludgeoning,
+        })
+        // Thick hides soften blows and shrug off poison
+        .with(Resistances { modifiers: vec![(DamageType::Bludgeoning, 25), (DamageType::Poison, 50)] })
+        .with(common_loot())
+        .build();
 }
 
 fn goblin(ecs: &mut World, x: i32, y: i32) {
-    monster(ecs, x, y, rltk::to_cp437('g'), "Goblin");
+    monster(ecs, x, y, rltk::to_cp437('g'), "Goblin")
+        .with(Description { text: "A small, vicious creature with sharp claws and a fear of fire.".to_string() })
+        .with(CombatStats { max_hp: 16, hp: 16, defense: 1, power: 4 })
+        .with(Experience { level: 1, xp: 0 })
+        .with(NaturalAttack {
+            name: "claws".to_string(),
+            hit_bonus: 1,
+            damage: DiceType::new(1, 4, 0),
+            damage_type: DamageType::Slashing,
+        })
+        .with(Resistances { modifiers: vec![(DamageType::Fire, -50), (DamageType::Magic, -25)] })
+        .with(common_loot())
+        .build();
+}
+
+fn slime(ecs: &mut World, x: i32, y: i32) {
+    monster(ecs, x, y, rltk::to_cp437('S'), "Slime")
+        .with(Description { text: "A quivering mass of acid that splits in two when struck down.".to_string() })
+        .with(CombatStats { max_hp: 20, hp: 20, defense: 0, power: 3 })
+        .with(Experience { level: 2, xp: 0 })
+        .with(NaturalAttack {
+            name: "acid".to_string(),
+            hit_bonus: 0,
+            damage: DiceType::new(1, 4, 0),
+            damage_type: DamageType::Poison,
+        })
+        .with(Resistances { modifiers: vec![(DamageType::Bludgeoning, 50), (DamageType::Slashing, -25)] })
+        .with(SplitsOnDeath {})
+        .build();
+}
+
+fn small_slime(ecs: &mut World, x: i32, y: i32) {
+    monster(ecs, x, y, rltk::to_cp437('s'), "Small Slime")
+        .with(Description { text: "A blob of acid broken off a larger slime.".to_string() })
+        .with(CombatStats { max_hp: 6, hp: 6, defense: 0, power: 2 })
+        .with(Experience { level: 1, xp: 0 })
+        .with(NaturalAttack {
+            name: "acid".to_string(),
+            hit_bonus: 0,
+            damage: DiceType::new(1, 3, 0),
+            damage_type: DamageType::Poison,
+        })
+        .with(Resistances { modifiers: vec![(DamageType::Bludgeoning, 50), (DamageType::Slashing, -25)] })
+        .build();
+}
+
+fn bloat(ecs: &mut World, x: i32, y: i32) {
+    monster(ecs, x, y, rltk::to_cp437('b'), "Bloat")
+        .with(Description { text: "A swollen creature full of gas that bursts into flame when it dies.".to_string() })
+        .with(CombatStats { max_hp: 8, hp: 8, defense: 0, power: 2 })
+        .with(Experience { level: 1, xp: 0 })
+        .with(NaturalAttack {
+            name: "bite".to_string(),
+            hit_bonus: 0,
+            damage: DiceType::new(1, 3, 0),
+            damage_type: DamageType::Slashing,
+        })
+        .with(ExplodesOnDeath { damage: 6, damage_type: DamageType::Fire, radius: 1 })
+        .build();
 }
 
-fn monster<S: ToString>(ecs: &mut World, x: i32, y: i32, glyph: rltk::FontCharType, name: S) -> Entity {
+/// Starts building a monster with everything monsters share. Callers add their own stats and attacks.
+fn monster<S: ToString>(ecs: &mut World, x: i32, y: i32, glyph: rltk::FontCharType, name: S) -> EntityBuilder<'_> {
     ecs.create_entity()
         .with(Position { x, y })
         .with(Renderable {
@@ -149,16 +378,10 @@ fn monster<S: ToString>(ecs: &mut World, x: i32, y: i32, glyph: rltk::FontCharTy
         .with(MovementSpeed {
             min_delay_ms: 1000,
             last_move_time: None,
+            encumbrance_delay_ms: 0,
         })
         .with(BlocksTile {})
-        .with(CombatStats {
-            max_hp: 16,
-            hp: 16,
-            defense: 1,
-            power: 4,
-        })
         .marked::<SimpleMarker<SerializeMe>>()
-        .build()
 }
 
 fn health_potion(ecs: &mut World, x: i32, y: i32) -> Entity {
@@ -171,9 +394,10 @@ fn health_potion(ecs: &mut World, x: i32, y: i32) -> Entity {
             render_order: 2,
         })
         .with(Name {
-            name: "Health Potion".to_string(),
+            name: HEALTH_POTION.to_string(),
         })
-        .with(Item {})
+        .with(Description { text: "Restores some health when drunk.".to_string() })
+        .with(Item { weight: 1.0, value: 50 })
         .with(Consumable {})
         .with(ProvidesHealing { heal_amount: 8 })
         .marked::<SimpleMarker<SerializeMe>>()
@@ -189,11 +413,205 @@ fn magic_missile_scroll(ecs: &mut World, x: i32, y: i32) -> Entity {
             bg: RGB::named(rltk::BLACK),
             render_order: 2
         })
-        .with(Name{ name : "Magic Missile Scroll".to_string() })
-        .with(Item {})
+        .with(Name{ name : MAGIC_MISSILE_SCROLL.to_string() })
+        .with(Description { text: "Hurls a bolt of force at something in sight.".to_string() })
+        .with(Item { weight: 0.5, value: 75 })
         .with(Consumable {})
         .with(Ranged { range: 6 })
-        .with(InflictsDamage { damage: 8 })
+        .with(InflictsDamage { damage: 8, damage_type: DamageType::Magic })
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build()
+}
+
+fn magic_mapping_scroll(ecs: &mut World, x: i32, y: i32) -> Entity {
+    ecs.create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437(')'),
+            fg: RGB::named(rltk::CYAN3),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 2,
+        })
+        .with(Name { name: MAGIC_MAPPING_SCROLL.to_string() })
+        .with(Description { text: "Reveals the layout of the whole level.".to_string() })
+        .with(Item { weight: 0.5, value: 60 })
+        .with(Consumable {})
+        .with(MagicMapper {})
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build()
+}
+
+fn telepathy_scroll(ecs: &mut World, x: i32, y: i32) -> Entity {
+    ecs.create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437(')'),
+            fg: RGB::named(rltk::PURPLE),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 2,
+        })
+        .with(Name { name: TELEPATHY_SCROLL.to_string() })
+        .with(Description { text: "Lets you sense every mind on this level for a while.".to_string() })
+        .with(Item { weight: 0.5, value: 60 })
+        .with(Consumable {})
+        .with(GrantsTelepathy { turns: 40 })
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build()
+}
+
+fn throwing_dagger(ecs: &mut World, x: i32, y: i32) -> Entity {
+    ecs.create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437('/'),
+            fg: RGB::named(rltk::LIGHT_GRAY),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 2,
+        })
+        .with(Name { name: "Throwing Dagger".to_string() })
+        .with(Description { text: "A small, balanced blade made for throwing.".to_string() })
+        .with(Item { weight: 1.0, value: 20 })
+        .with(ThrowingWeapon { damage: DiceType::new(1, 4, 1), damage_type: DamageType::Slashing })
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build()
+}
+
+/// A pile of gold, which goes straight into the wallet of whoever picks it up
+fn gold_pile(ecs: &mut World, x: i32, y: i32) -> Entity {
+    let amount = ecs.write_resource::<RandomNumberGenerator>().roll_dice(3, 10);
+    ecs.create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437('$'),
+            fg: RGB::named(rltk::GOLD),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 2,
+        })
+        .with(Name { name: format!("{} gold", amount) })
+        .with(Description { text: "Coins that any shopkeeper will gladly take.".to_string() })
+        .with(Item { weight: 0.0, value: 0 })
+        .with(Gold { amount })
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build()
+}
+
+fn iron_key(ecs: &mut World, x: i32, y: i32) -> Entity {
+    ecs.create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437('-'),
+            fg: RGB::named(rltk::GOLD),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 2,
+        })
+        .with(Name { name: "Iron Key".to_string() })
+        .with(Description { text: "A heavy key that opens one locked chest.".to_string() })
+        .with(Item { weight: 0.2, value: 15 })
+        .with(Key {})
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build()
+}
+
+/// A chest with a few random items inside, which is sometimes locked
+fn chest(ecs: &mut World, x: i32, y: i32) -> Entity {
+    let (locked, num_items) = {
+        let mut rng = ecs.write_resource::<RandomNumberGenerator>();
+        (rng.roll_dice(1, LOCKED_CHEST_CHANCE) == 1, rng.roll_dice(1, MAX_CHEST_ITEMS))
+    };
+    let chest = ecs
+        .create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437('='),
+            fg: RGB::named(rltk::CHOCOLATE),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 1,
+        })
+        .with(Name { name: "Chest".to_string() })
+        .with(Description { text: "A sturdy wooden chest that might hold something useful.".to_string() })
+        .with(BlocksTile {})
+        .with(Container {})
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build();
+    if locked {
+        ecs.write_storage::<Locked>().insert(chest, Locked {}).expect("Unable to lock chest");
+    }
+
+    for _ in 0..num_items {
+        let item = random_item(ecs, x, y);
+        ecs.write_storage::<Position>().remove(item);
+        let mut contents = ecs.write_storage::<InContainer>();
+        contents.insert(item, InContainer { container: chest }).expect("Unable to fill chest");
+    }
+    chest
+}
+
+/// Spawns a shopkeeper with some stock to sell
+pub fn shopkeeper(ecs: &mut World, x: i32, y: i32) -> Entity {
+    let vendor = ecs
+        .create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437('@'),
+            fg: RGB::named(rltk::GOLD),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 1,
+        })
+        .with(Name { name: "Shopkeeper".to_string() })
+        .with(Description { text: "A trader who buys and sells all sorts of goods.".to_string() })
+        .with(BlocksTile {})
+        .with(Vendor {})
+        .with(Gold { amount: SHOPKEEPER_GOLD })
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build();
+
+    let stock: [fn(&mut World, i32, i32) -> Entity; 7] =
+        [health_potion, health_potion, magic_missile_scroll, magic_missile_scroll, rations, rations, throwing_dagger];
+    for spawn in stock {
+        let item = spawn(ecs, x, y);
+        ecs.write_storage::<Position>().remove(item);
+        ecs.write_storage::<InBackpack>().insert(item, InBackpack { owner: vendor }).expect("Unable to stock item");
+    }
+    vendor
+}
+
+/// Leaves the remains of something that died, which can be picked up and eaten in a pinch
+pub fn corpse(ecs: &mut World, name: &str, x: i32, y: i32) -> Entity {
+    ecs.create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437('%'),
+            fg: RGB::named(rltk::DARK_RED),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 2,
+        })
+        .with(Name {
+            name: format!("{} Corpse", name),
+        })
+        .with(Description { text: format!("What is left of a {}. Edible, if you're desperate.", name) })
+        .with(Item { weight: 20.0, value: 0 })
+        .with(Consumable {})
+        .with(ProvidesFood {})
+        .marked::<SimpleMarker<SerializeMe>>()
+        .build()
+}
+
+fn rations(ecs: &mut World, x: i32, y: i32) -> Entity {
+    ecs.create_entity()
+        .with(Position { x, y })
+        .with(Renderable {
+            glyph: rltk::to_cp437('%'),
+            fg: RGB::named(rltk::GREEN),
+            bg: RGB::named(rltk::BLACK),
+            render_order: 2,
+        })
+        .with(Name {
+            name: "Rations".to_string(),
+        })
+        .with(Description { text: "Dried food that keeps hunger at bay.".to_string() })
+        .with(Item { weight: 2.0, value: 10 })
+        .with(Consumable {})
+        .with(ProvidesFood {})
         .marked::<SimpleMarker<SerializeMe>>()
         .build()
 }
@@ -0,0 +1,38 @@
+use specs::prelude::*;
+
+use crate::components::Telepathy;
+use crate::gamelog::LogCategory;
+use crate::{GameLog, RunState};
+
+/// Counts down temporary effects, such as telepathy, each player turn
+pub struct StatusEffectSystem {}
+
+impl<'a> System<'a> for StatusEffectSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, Entity>,
+        ReadExpect<'a, RunState>,
+        WriteExpect<'a, GameLog>,
+        WriteStorage<'a, Telepathy>,
+    );
+
+    fn run(&mut self, (entities, player_entity, runstate, mut log, mut telepathy): Self::SystemData) {
+        if *runstate != RunState::PlayerTurn {
+            return;
+        }
+
+        let mut expired: Vec<Entity> = Vec::new();
+        for (entity, effect) in (&entities, &mut telepathy).join() {
+            effect.turns -= 1;
+            if effect.turns <= 0 {
+                expired.push(entity);
+            }
+        }
+        for entity in expired {
+            telepathy.remove(entity);
+            if entity == *player_entity {
+                log.add(LogCategory::Status, "Your telepathy fades.");
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use rltk::{DijkstraMap, Point};
+use specs::prelude::*;
+
+use crate::gamelog::LogCategory;
+use crate::identification::display_name;
+use crate::{GameLog, Item, Map, Monster, Name, Position, Viewshed};
+
+/// How far the explore map floods, comfortably more than any path across the map
+const EXPLORE_DEPTH: f32 = 1000.0;
+
+/// Walking the player does on their own, a step each time they are able to move
+#[derive(Debug, Clone, PartialEq)]
+pub enum AutoMove {
+    /// Heads for the nearest unrevealed tile until there are none left to reach
+    Explore,
+    /// Remaining steps of the path to a clicked tile, as map indices
+    Travel(Vec<usize>),
+}
+
+/// Plans a path to a revealed tile, or None if it can't be reached
+pub fn plan_travel(ecs: &World, target: Point) -> Option<AutoMove> {
+    let map = ecs.fetch::<Map>();
+    let player_pos = ecs.fetch::<Point>();
+
+    if target.x < 0 || target.x >= map.width || target.y < 0 || target.y >= map.height {
+        return None;
+    }
+    let target_idx = map.xy_idx(target.x, target.y);
+    let steps = if map.revealed_tiles[target_idx] {
+        find_path(&map, map.xy_idx(player_pos.x, player_pos.y), target_idx)
+    } else {
+        None
+    };
+    if steps.is_none() {
+        ecs.fetch_mut::<GameLog>().add(LogCategory::Status, "You can't find a way there.");
+    }
+    steps.map(AutoMove::Travel)
+}
+
+/// Steps from one tile to another, not counting the tile it starts on
+fn find_path(map: &Map, start_idx: usize, target_idx: usize) -> Option<Vec<usize>> {
+    let path = rltk::a_star_search(start_idx, target_idx, map);
+    if !path.success || path.steps.len() < 2 {
+        return None;
+    }
+    Some(path.steps[1..].to_vec())
+}
+
+/// Works out the direction of the next automatic step, or None once there is nowhere left to go or a
+/// monster comes into view
+pub fn next_step(ecs: &World, auto_move: &mut AutoMove) -> Option<Point> {
+    let map = ecs.fetch::<Map>();
+    let player_pos = *ecs.fetch::<Point>();
+    let mut log = ecs.fetch_mut::<GameLog>();
+
+    if let Some(monster) = monster_in_view(ecs) {
+        log.add(LogCategory::System, format!("You stop as you see a {}.", monster));
+        return None;
+    }
+
+    let next_idx = match auto_move {
+        AutoMove::Explore => {
+            let unexplored: Vec<usize> =
+                (0..map.revealed_tiles.len()).filter(|idx| !map.revealed_tiles[*idx]).collect();
+            let dijkstra = DijkstraMap::new(map.width, map.height, &unexplored, &*map, EXPLORE_DEPTH);
+            let next = DijkstraMap::find_lowest_exit(&dijkstra, map.xy_idx(player_pos.x, player_pos.y), &*map)
+                .filter(|idx| dijkstra.map[*idx] < f32::MAX);
+            if next.is_none() {
+                log.add(LogCategory::System, "There is nothing left to explore.");
+            }
+            next
+        }
+        AutoMove::Travel(steps) => {
+            let player_idx = map.xy_idx(player_pos.x, player_pos.y);
+            // A step is only used up once the player is standing on it, since a trap can hold them back
+            if steps.first() == Some(&player_idx) {
+                steps.remove(0);
+            }
+            let target_idx = *steps.last()?;
+            let next = Point::new(steps[0] as i32 % map.width, steps[0] as i32 / map.width);
+            if (next.x - player_pos.x).abs() > 1 || (next.y - player_pos.y).abs() > 1 {
+                // Knocked off the path, by a teleport trap say, so head for the destination from here
+                *steps = find_path(&map, player_idx, target_idx).or_else(|| {
+                    log.add(LogCategory::System, "You can no longer find a way there.");
+                    None
+                })?;
+            }
+            Some(steps[0])
+        }
+    }?;
+
+    Some(Point::new(next_idx as i32 % map.width - player_pos.x, next_idx as i32 / map.width - player_pos.y))
+}
+
+/// Checks for an item where the player is standing, which is worth stopping to look at
+pub fn found_item(ecs: &World) -> bool {
+    let player_pos = *ecs.fetch::<Point>();
+    let entities = ecs.entities();
+    let positions = ecs.read_storage::<Position>();
+    let items = ecs.read_storage::<Item>();
+
+    let Some(item) = (&entities, &positions, &items)
+        .join()
+        .find(|(_, pos, _)| pos.x == player_pos.x && pos.y == player_pos.y)
+        .map(|(entity, _, _)| entity)
+    else {
+        return false;
+    };
+    let name = display_name(ecs, item);
+    ecs.fetch_mut::<GameLog>().add(LogCategory::Item, format!("You see a {} here.", name));
+    true
+}
+
+fn monster_in_view(ecs: &World) -> Option<String> {
+    let player_entity = ecs.fetch::<Entity>();
+    let viewsheds = ecs.read_storage::<Viewshed>();
+    let positions = ecs.read_storage::<Position>();
+    let monsters = ecs.read_storage::<Monster>();
+    let names = ecs.read_storage::<Name>();
+
+    let viewshed = viewsheds.get(*player_entity)?;
+    (&positions, &monsters, &names)
+        .join()
+        .find(|(pos, _, _)| viewshed.visible_tiles.contains(&Point::new(pos.x, pos.y)))
+        .map(|(_, _, name)| name.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::TileType;
+
+    const SIZE: i32 = 10;
+
+    /// An open room with a wall around the edge, all of it revealed, and the player standing at `start`
+    fn world_with_room(start: Point) -> World {
+        let count = (SIZE * SIZE) as usize;
+        let mut map = Map {
+            tiles: vec![TileType::Floor; count],
+            rooms: Vec::new(),
+            width: SIZE,
+            height: SIZE,
+            revealed_tiles: vec![true; count],
+            visible_tiles: vec![false; count],
+            blocked: vec![false; count],
+            tile_content: vec![Vec::new(); count],
+        };
+        for x in 0..SIZE {
+            for y in 0..SIZE {
+                if x == 0 || y == 0 || x == SIZE - 1 || y == SIZE - 1 {
+                    let idx = map.xy_idx(x, y);
+                    map.tiles[idx] = TileType::Wall;
+                }
+            }
+        }
+        map.populate_blocked();
+
+        let mut world = World::new();
+        world.register::<Viewshed>();
+        world.register::<Position>();
+        world.register::<Monster>();
+        world.register::<Name>();
+        let player = world.create_entity().with(Viewshed { visible_tiles: Vec::new(), range: 8, dirty: false }).build();
+        world.insert(player);
+        world.insert(map);
+        world.insert(start);
+        world.insert(GameLog::new(10));
+        world
+    }
+
+    #[test]
+    fn travel_plans_a_path_to_the_target() {
+        let world = world_with_room(Point::new(2, 2));
+        let Some(AutoMove::Travel(steps)) = plan_travel(&world, Point::new(6, 2)) else {
+            panic!("no path planned");
+        };
+        let map = world.fetch::<Map>();
+        assert_eq!(steps, (3..=6).map(|x| map.xy_idx(x, 2)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn travel_needs_a_revealed_target() {
+        let world = world_with_room(Point::new(2, 2));
+        let idx = world.fetch::<Map>().xy_idx(6, 2);
+        world.fetch_mut::<Map>().revealed_tiles[idx] = false;
+        assert!(plan_travel(&world, Point::new(6, 2)).is_none());
+        assert!(plan_travel(&world, Point::new(SIZE, 2)).is_none());
+    }
+
+    #[test]
+    fn travel_to_a_wall_says_there_is_no_way() {
+        let world = world_with_room(Point::new(2, 2));
+        assert!(plan_travel(&world, Point::new(0, 2)).is_none());
+        let log = world.fetch::<GameLog>();
+        let entry = log.entries.back().unwrap();
+        assert_eq!(entry.category, LogCategory::Status);
+        assert_eq!(entry.segments[0].text, "You can't find a way there.");
+    }
+
+    #[test]
+    fn travel_waits_for_a_step_that_did_not_happen() {
+        let world = world_with_room(Point::new(2, 2));
+        let mut travel = plan_travel(&world, Point::new(5, 2)).unwrap();
+
+        assert_eq!(next_step(&world, &mut travel), Some(Point::new(1, 0)));
+        // Held in place by a trap, the same step is tried again
+        assert_eq!(next_step(&world, &mut travel), Some(Point::new(1, 0)));
+        let AutoMove::Travel(steps) = &travel else { unreachable!() };
+        assert_eq!(steps.len(), 3);
+
+        *world.fetch_mut::<Point>() = Point::new(3, 2);
+        assert_eq!(next_step(&world, &mut travel), Some(Point::new(1, 0)));
+        let AutoMove::Travel(steps) = &travel else { unreachable!() };
+        assert_eq!(steps.len(), 2);
+    }
+
+    #[test]
+    fn travel_replans_after_being_moved_off_the_path() {
+        let world = world_with_room(Point::new(2, 2));
+        let mut travel = plan_travel(&world, Point::new(7, 2)).unwrap();
+        next_step(&world, &mut travel);
+
+        // Teleported into the far corner
+        *world.fetch_mut::<Point>() = Point::new(7, 7);
+        assert_eq!(next_step(&world, &mut travel), Some(Point::new(0, -1)));
+        let AutoMove::Travel(steps) = &travel else { unreachable!() };
+        assert_eq!(steps.len(), 5);
+    }
+}
@@ -0,0 +1,111 @@
+use rltk::{Point, RandomNumberGenerator};
+use specs::prelude::*;
+
+use crate::components::{EntityMoved, EntryTrigger, Hidden, Immobilized, Immobilizes, InflictsDamage, Teleports};
+use crate::gamelog::LogCategory;
+use crate::map::TileType;
+use crate::{CombatStats, GameLog, Map, Name, Position, SufferDamage, Viewshed};
+
+/// Sets off entry triggers, such as traps, under anything that moved this tick
+pub struct TriggerSystem {}
+
+impl<'a> System<'a> for TriggerSystem {
+    type SystemData = (
+        ReadExpect<'a, Map>,
+        ReadExpect<'a, Entity>,
+        WriteExpect<'a, Point>,
+        WriteExpect<'a, GameLog>,
+        WriteExpect<'a, RandomNumberGenerator>,
+        Entities<'a>,
+        WriteStorage<'a, EntityMoved>,
+        WriteStorage<'a, Position>,
+        ReadStorage<'a, EntryTrigger>,
+        WriteStorage<'a, Hidden>,
+        ReadStorage<'a, Name>,
+        ReadStorage<'a, CombatStats>,
+        ReadStorage<'a, InflictsDamage>,
+        WriteStorage<'a, SufferDamage>,
+        ReadStorage<'a, Immobilizes>,
+        WriteStorage<'a, Immobilized>,
+        ReadStorage<'a, Teleports>,
+        WriteStorage<'a, Viewshed>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            map,
+            player_entity,
+            mut player_pos,
+            mut log,
+            mut rng,
+            entities,
+            mut moved,
+            mut positions,
+            entry_triggers,
+            mut hidden,
+            names,
+            combat_stats,
+            inflicts_damage,
+            mut suffer_damage,
+            immobilizes,
+            mut immobilized,
+            teleports,
+            mut viewsheds,
+        ): Self::SystemData,
+    ) {
+        let mut teleported: Vec<Entity> = Vec::new();
+        for (entity, _moved, pos) in (&entities, &moved, &positions).join() {
+            let idx = map.xy_idx(pos.x, pos.y);
+            let seen = entity == *player_entity || map.is_visible(pos.x, pos.y);
+            for trigger in map.tile_content[idx].iter().filter(|e| **e != entity && entry_triggers.contains(**e)) {
+                // Only the living set off traps
+                if !combat_stats.contains(entity) {
+                    continue;
+                }
+
+                if seen {
+                    let victim = &names.get(entity).unwrap().name;
+                    log.add(
+                        LogCategory::Combat,
+                        format!("{} triggers a {}!", victim, names.get(*trigger).unwrap().name),
+                    );
+                    hidden.remove(*trigger);
+                }
+                if let Some(damage) = inflicts_damage.get(*trigger) {
+                    SufferDamage::new_damage(&mut suffer_damage, entity, damage.damage, damage.damage_type, None);
+                }
+                if let Some(trap) = immobilizes.get(*trigger) {
+                    immobilized.insert(entity, Immobilized { turns: trap.turns }).expect("Unable to immobilize");
+                }
+                if teleports.contains(*trigger) {
+                    teleported.push(entity);
+                }
+            }
+        }
+        moved.clear();
+
+        // Anywhere on open floor will do
+        for entity in teleported {
+            let destination = loop {
+                let x = rng.roll_dice(1, map.width - 2);
+                let y = rng.roll_dice(1, map.height - 2);
+                let idx = map.xy_idx(x, y);
+                if map.tiles[idx] == TileType::Floor && !map.blocked[idx] {
+                    break Point::new(x, y);
+                }
+            };
+            if let Some(pos) = positions.get_mut(entity) {
+                pos.x = destination.x;
+                pos.y = destination.y;
+            }
+            if let Some(viewshed) = viewsheds.get_mut(entity) {
+                viewshed.dirty = true;
+            }
+            if entity == *player_entity {
+                *player_pos = destination;
+                log.add(LogCategory::Status, "You are whisked away!");
+            }
+        }
+    }
+}